@@ -0,0 +1,557 @@
+use crate::core::RavenError;
+use crate::manifest;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    TarGz,
+    TarXz,
+    TarBz2,
+    TarZst,
+    Tar,
+    Zip,
+}
+
+impl ArchiveFormat {
+    // Magic bytes win; the URL extension is only a fallback for formats
+    // without a reliable signature.
+    pub fn detect(path: &Path, url: &str) -> Result<Self, RavenError> {
+        let mut header = [0u8; 262];
+        let mut f = File::open(path)?;
+        let mut len = 0;
+        while len < header.len() {
+            let n = f.read(&mut header[len..])?;
+            if n == 0 {
+                break;
+            }
+            len += n;
+        }
+        let header = &header[..len];
+
+        if header.starts_with(&[0x1f, 0x8b]) {
+            return Ok(Self::TarGz);
+        }
+        if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            return Ok(Self::TarXz);
+        }
+        if header.starts_with(b"BZh") {
+            return Ok(Self::TarBz2);
+        }
+        if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            return Ok(Self::TarZst);
+        }
+        if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            return Ok(Self::Zip);
+        }
+        if header.len() >= 262 && &header[257..262] == b"ustar" {
+            return Ok(Self::Tar);
+        }
+
+        Self::from_extension(url).ok_or_else(|| {
+            RavenError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unrecognized archive format: {}", url),
+            ))
+        })
+    }
+
+    fn from_extension(url: &str) -> Option<Self> {
+        let name = url.split(['?', '#']).next().unwrap_or(url);
+        let formats = [
+            (".tar.gz", Self::TarGz),
+            (".tgz", Self::TarGz),
+            (".tar.xz", Self::TarXz),
+            (".txz", Self::TarXz),
+            (".tar.bz2", Self::TarBz2),
+            (".tbz2", Self::TarBz2),
+            (".tar.zst", Self::TarZst),
+            (".tzst", Self::TarZst),
+            (".tar", Self::Tar),
+            (".zip", Self::Zip),
+        ];
+        formats
+            .into_iter()
+            .find(|(ext, _)| name.ends_with(ext))
+            .map(|(_, format)| format)
+    }
+}
+
+// Upper bounds guarding against decompression bombs
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractLimits {
+    pub max_bytes: u64,
+    pub max_entries: u64,
+}
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 16 * 1024 * 1024 * 1024,
+            max_entries: 1_000_000,
+        }
+    }
+}
+
+// Running totals checked against the limits as entries are unpacked
+struct Budget {
+    limits: ExtractLimits,
+    bytes: u64,
+    entries: u64,
+}
+
+impl Budget {
+    fn charge(&mut self, size: u64) -> Result<(), RavenError> {
+        self.entries += 1;
+        if self.entries > self.limits.max_entries {
+            return Err(RavenError::ArchiveError(format!(
+                "Archive exceeds the limit of {} entries",
+                self.limits.max_entries
+            )));
+        }
+        self.charge_bytes(size)
+    }
+
+    fn charge_bytes(&mut self, size: u64) -> Result<(), RavenError> {
+        self.bytes = self.bytes.saturating_add(size);
+        if self.bytes > self.limits.max_bytes {
+            return Err(RavenError::ArchiveError(format!(
+                "Archive exceeds the limit of {} extracted bytes",
+                self.limits.max_bytes
+            )));
+        }
+        Ok(())
+    }
+
+    fn remaining_bytes(&self) -> u64 {
+        self.limits.max_bytes.saturating_sub(self.bytes)
+    }
+}
+
+// Blocking: call from spawn_blocking. Entry mtimes are kept as recorded;
+// returns the newest one, if the archive records any.
+pub fn extract(
+    archive: &Path,
+    dest: &Path,
+    format: ArchiveFormat,
+    limits: ExtractLimits,
+) -> Result<Option<i64>, RavenError> {
+    let f = BufReader::new(File::open(archive)?);
+    let mut budget = Budget {
+        limits,
+        bytes: 0,
+        entries: 0,
+    };
+
+    match format {
+        ArchiveFormat::TarGz => unpack_tar(flate2::read::GzDecoder::new(f), dest, &mut budget),
+        ArchiveFormat::TarXz => unpack_tar(xz2::read::XzDecoder::new(f), dest, &mut budget),
+        ArchiveFormat::TarBz2 => unpack_tar(bzip2::read::BzDecoder::new(f), dest, &mut budget),
+        ArchiveFormat::TarZst => {
+            unpack_tar(zstd::stream::read::Decoder::new(f)?, dest, &mut budget)
+        }
+        ArchiveFormat::Tar => unpack_tar(f, dest, &mut budget),
+        ArchiveFormat::Zip => unpack_zip(f, dest, &mut budget),
+    }
+}
+
+fn unpack_tar<R: Read>(
+    reader: R,
+    dest: &Path,
+    budget: &mut Budget,
+) -> Result<Option<i64>, RavenError> {
+    let mut ar = tar::Archive::new(reader);
+    ar.set_preserve_permissions(true);
+
+    let entries = ar
+        .entries()
+        .map_err(|e| RavenError::ArchiveError(format!("Unreadable archive: {}", e)))?;
+
+    let mut newest = None;
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let mut entry =
+            entry.map_err(|e| RavenError::ArchiveError(format!("Corrupt archive entry: {}", e)))?;
+
+        let path = entry
+            .path()
+            .map_err(|e| RavenError::ArchiveError(format!("Invalid entry path: {}", e)))?
+            .into_owned();
+        check_entry_path(&path)?;
+        budget.charge(entry.header().size().unwrap_or(0))?;
+
+        // Parents must be real directories: an earlier symlink entry would
+        // otherwise redirect this one (and its link target) elsewhere
+        let kind = entry.header().entry_type();
+        let mtime = entry.header().mtime().ok().map(|t| t as i64);
+        newest = newest.max(mtime);
+        if kind.is_dir() {
+            create_dirs_in(dest, &path)?;
+            dirs.push((path.clone(), mtime));
+        } else {
+            create_dirs_in(dest, path.parent().unwrap_or(Path::new("")))?;
+        }
+        if kind.is_symlink() || kind.is_hard_link() {
+            let target = entry
+                .link_name()
+                .map_err(|e| RavenError::ArchiveError(format!("Invalid link target: {}", e)))?
+                .ok_or_else(|| {
+                    RavenError::ArchiveError(format!("Link without target: {}", path.display()))
+                })?;
+            // Symlinks resolve relative to their own directory, hard links to the archive root
+            let base = if kind.is_symlink() {
+                path.parent().unwrap_or(Path::new(""))
+            } else {
+                Path::new("")
+            };
+            check_link_target(&path, base, &target)?;
+        } else if kind.is_character_special() || kind.is_block_special() {
+            return Err(RavenError::ArchiveError(format!(
+                "Device node not allowed in source archive: {}",
+                path.display()
+            )));
+        }
+
+        let unpacked = entry.unpack_in(dest).map_err(|e| {
+            RavenError::ArchiveError(format!("Failed to extract {}: {}", path.display(), e))
+        })?;
+        if !unpacked {
+            return Err(RavenError::ArchiveError(format!(
+                "Entry escapes the source directory: {}",
+                path.display()
+            )));
+        }
+    }
+
+    // Unpacking into a directory bumped its mtime again
+    restore_dir_mtimes(dest, &dirs)?;
+    Ok(newest)
+}
+
+fn restore_dir_mtimes(dest: &Path, dirs: &[(PathBuf, Option<i64>)]) -> Result<(), RavenError> {
+    for (path, mtime) in dirs {
+        if let Some(mtime) = mtime {
+            manifest::set_mtime(&dest.join(path), *mtime)?;
+        }
+    }
+    Ok(())
+}
+
+fn unpack_zip<R: Read + std::io::Seek>(
+    reader: R,
+    dest: &Path,
+    budget: &mut Budget,
+) -> Result<Option<i64>, RavenError> {
+    let mut zip = zip::ZipArchive::new(reader)
+        .map_err(|e| RavenError::ArchiveError(format!("Unreadable zip archive: {}", e)))?;
+
+    let mut newest = None;
+    let mut dirs = Vec::new();
+    for i in 0..zip.len() {
+        let mut file = zip
+            .by_index(i)
+            .map_err(|e| RavenError::ArchiveError(format!("Corrupt zip entry: {}", e)))?;
+
+        let path = file.enclosed_name().map(Path::to_path_buf).ok_or_else(|| {
+            RavenError::ArchiveError(format!("Unsafe zip entry path: {}", file.name()))
+        })?;
+        check_entry_path(&path)?;
+        // The declared size may lie; bytes are charged as they are read
+        budget.charge(0)?;
+
+        let out = dest.join(&path);
+        let mode = file.unix_mode();
+        let is_symlink = mode.map(|m| m & 0o170000 == 0o120000).unwrap_or(false);
+
+        let mtime = zip_mtime(file.last_modified());
+        newest = newest.max(Some(mtime));
+
+        // Earlier entries may have planted symlinks along the way
+        if file.is_dir() {
+            create_dirs_in(dest, &path)?;
+            dirs.push((path, Some(mtime)));
+            continue;
+        }
+        create_dirs_in(dest, path.parent().unwrap_or(Path::new("")))?;
+        if out.symlink_metadata().is_ok() {
+            return Err(RavenError::ArchiveError(format!(
+                "Duplicate zip entry: {}",
+                path.display()
+            )));
+        }
+
+        let mut limited = (&mut file).take(budget.remaining_bytes() + 1);
+        if is_symlink {
+            let mut target = String::new();
+            limited.read_to_string(&mut target)?;
+            budget.charge_bytes(target.len() as u64)?;
+            let target = Path::new(&target);
+            check_link_target(&path, path.parent().unwrap_or(Path::new("")), target)?;
+            std::os::unix::fs::symlink(target, &out)?;
+        } else {
+            let mut writer = File::create(&out)?;
+            let copied = std::io::copy(&mut limited, &mut writer).map_err(|e| {
+                RavenError::ArchiveError(format!("Failed to extract {}: {}", path.display(), e))
+            })?;
+            budget.charge_bytes(copied)?;
+            if let Some(mode) = mode {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(&out, std::fs::Permissions::from_mode(mode & 0o7777))?;
+            }
+        }
+        manifest::set_mtime(&out, mtime)?;
+    }
+
+    restore_dir_mtimes(dest, &dirs)?;
+    Ok(newest)
+}
+
+// Zip stores a zone-less DOS date and time; it is taken as UTC
+fn zip_mtime(time: zip::DateTime) -> i64 {
+    let (year, month, day) = (
+        i64::from(time.year()),
+        i64::from(time.month()),
+        i64::from(time.day()),
+    );
+    // Days since 1970-01-01 from a civil date (Howard Hinnant's algorithm)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    days * 86_400
+        + i64::from(time.hour()) * 3600
+        + i64::from(time.minute()) * 60
+        + i64::from(time.second())
+}
+
+// Create `relative` below `dest` one component at a time, refusing to pass
+// through anything that is not a real directory (i.e. a symlink)
+fn create_dirs_in(dest: &Path, relative: &Path) -> Result<(), RavenError> {
+    let mut current = dest.to_path_buf();
+    for component in relative.components() {
+        let Component::Normal(name) = component else {
+            continue;
+        };
+        current.push(name);
+        match current.symlink_metadata() {
+            Ok(meta) if meta.is_dir() => {}
+            Ok(_) => {
+                return Err(RavenError::ArchiveError(format!(
+                    "Entry escapes the source directory through {}",
+                    current.display()
+                )))
+            }
+            Err(_) => std::fs::create_dir(&current)?,
+        }
+    }
+    Ok(())
+}
+
+fn check_entry_path(path: &Path) -> Result<(), RavenError> {
+    for component in path.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            _ => {
+                return Err(RavenError::ArchiveError(format!(
+                    "Unsafe entry path (absolute or '..'): {}",
+                    path.display()
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+// Resolve `target` lexically from `base` and make sure it never climbs above
+// the root. `..` is only allowed before any name: `base` is made of real
+// directories, but a name may be (or later become) a symlink, after which
+// `..` no longer means what it says.
+fn check_link_target(entry: &Path, base: &Path, target: &Path) -> Result<(), RavenError> {
+    let escapes = || {
+        RavenError::ArchiveError(format!(
+            "Link escapes the source directory: {} -> {}",
+            entry.display(),
+            target.display()
+        ))
+    };
+
+    if target.is_absolute() {
+        return Err(escapes());
+    }
+
+    let mut depth: usize = base
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .count();
+    let mut named = false;
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => {
+                depth += 1;
+                named = true;
+            }
+            Component::CurDir => {}
+            Component::ParentDir if named => return Err(escapes()),
+            Component::ParentDir => depth = depth.checked_sub(1).ok_or_else(escapes)?,
+            _ => return Err(escapes()),
+        }
+    }
+    Ok(())
+}
+
+// File name a non-extracted source is staged under
+pub fn file_name_from_url(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .filter(|s| !s.is_empty())
+        .unwrap_or("source")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("raven-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn budget() -> Budget {
+        Budget {
+            limits: ExtractLimits::default(),
+            bytes: 0,
+            entries: 0,
+        }
+    }
+
+    // Tar of (path, symlink target) entries, in order
+    fn symlink_tar(links: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, target) in links {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            header.set_mode(0o777);
+            builder.append_link(&mut header, path, target).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn entry_paths_must_stay_relative() {
+        assert!(check_entry_path(Path::new("pkg-1.0/src/main.c")).is_ok());
+        assert!(check_entry_path(Path::new("./pkg-1.0")).is_ok());
+        assert!(check_entry_path(Path::new("../evil")).is_err());
+        assert!(check_entry_path(Path::new("pkg/../../evil")).is_err());
+        assert!(check_entry_path(Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn symlink_targets_stay_inside() {
+        let check = |entry: &str, target: &str| {
+            let entry = Path::new(entry);
+            check_link_target(entry, entry.parent().unwrap(), Path::new(target))
+        };
+        assert!(check("pkg/lib/libfoo.so", "libfoo.so.1").is_ok());
+        assert!(check("pkg/bin/tool", "../lib/tool").is_ok());
+        assert!(check("pkg/bin/tool", "../../../etc/passwd").is_err());
+        assert!(check("link", "..").is_err());
+        assert!(check("pkg/link", "/etc/passwd").is_err());
+        // `..` after a name could follow a symlink
+        assert!(check("pkg/link", "x/y/..").is_err());
+    }
+
+    #[test]
+    fn hard_link_targets_resolve_from_the_root() {
+        let check = |target: &str| {
+            check_link_target(Path::new("pkg/deep/hl"), Path::new(""), Path::new(target))
+        };
+        assert!(check("pkg/file").is_ok());
+        assert!(check("../file").is_err());
+        assert!(check("/etc/shadow").is_err());
+    }
+
+    #[test]
+    fn symlink_chains_cannot_escape() {
+        // l1 -> . makes l1/l2 land in the root, where `..` leaves it
+        let dest = scratch("chain");
+        let tar = symlink_tar(&[("l1", "."), ("l1/l2", "..")]);
+        let result = unpack_tar(tar.as_slice(), &dest, &mut budget());
+        assert!(result.is_err());
+        assert!(!dest.join("l2").exists());
+
+        // Names that only later become symlinks
+        let dest = scratch("late");
+        let tar = symlink_tar(&[("a", "x/y/.."), ("x", "."), ("y", ".")]);
+        assert!(unpack_tar(tar.as_slice(), &dest, &mut budget()).is_err());
+        let _ = std::fs::remove_dir_all(&dest);
+    }
+
+    #[test]
+    fn recorded_mtimes_survive_extraction() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut dir = tar::Header::new_gnu();
+        dir.set_entry_type(tar::EntryType::Directory);
+        dir.set_size(0);
+        dir.set_mode(0o755);
+        dir.set_mtime(1_000_000_000);
+        builder
+            .append_data(&mut dir, "pkg", std::io::empty())
+            .unwrap();
+        let mut file = tar::Header::new_gnu();
+        file.set_size(2);
+        file.set_mode(0o644);
+        file.set_mtime(1_100_000_000);
+        builder
+            .append_data(&mut file, "pkg/file", &b"hi"[..])
+            .unwrap();
+        let tar = builder.into_inner().unwrap();
+
+        let dest = scratch("mtime");
+        let newest = unpack_tar(tar.as_slice(), &dest, &mut budget()).unwrap();
+        assert_eq!(newest, Some(1_100_000_000));
+        // The directory keeps its own time although a file was added to it later
+        let mtime = |p: &str| {
+            use std::os::unix::fs::MetadataExt;
+            dest.join(p).symlink_metadata().unwrap().mtime()
+        };
+        assert_eq!(mtime("pkg"), 1_000_000_000);
+        assert_eq!(mtime("pkg/file"), 1_100_000_000);
+        let _ = std::fs::remove_dir_all(&dest);
+    }
+
+    #[test]
+    fn zip_times_convert_to_unix() {
+        let time = |y, mo, d, h, mi, s| {
+            zip_mtime(zip::DateTime::from_date_and_time(y, mo, d, h, mi, s).unwrap())
+        };
+        assert_eq!(time(1980, 1, 1, 0, 0, 0), 315_532_800);
+        assert_eq!(time(2000, 2, 29, 12, 30, 10), 951_827_410);
+        assert_eq!(time(2024, 12, 31, 23, 59, 58), 1_735_689_598);
+    }
+
+    #[test]
+    fn regular_symlinks_unpack() {
+        let dest = scratch("plain");
+        let tar = symlink_tar(&[("pkg/lib/libfoo.so", "libfoo.so.1"), ("pkg/up", "../pkg")]);
+        unpack_tar(tar.as_slice(), &dest, &mut budget()).unwrap();
+        assert!(dest
+            .join("pkg/lib/libfoo.so")
+            .symlink_metadata()
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        let _ = std::fs::remove_dir_all(&dest);
+    }
+}
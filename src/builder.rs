@@ -1,23 +1,554 @@
-use crate::core::{RavenError, Recipe};
+use crate::archive::{self, ArchiveFormat, ExtractLimits};
+use crate::buildlog;
+use crate::checksum::{hash_prefix, verify_file, Checksums, MultiHasher};
+use crate::config::{RavenConfig, TargetProfile};
+use crate::core::{PackageName, Phase, RavenError, Recipe, Source};
+use crate::elfdeps::SonameScan;
+use crate::fetch::{
+    scheme_of, shell_quote, CommandFetcher, Fetcher, FetcherRegistry, FileFetcher, GitFetcher,
+    HttpFetcher,
+};
+use crate::keyring::Keyring;
+use crate::manifest;
+use crate::postprocess::PostProcessor;
 use crate::sandbox::ScriptSandbox;
-use crate::ui::{create_download_bar, create_spinner, log_success};
-use sha2::{Digest, Sha256};
+use crate::split;
+use crate::ui::{create_spinner, log_error, log_success};
+use crate::vcs::GitSource;
+use indicatif::MultiProgress;
+use std::collections::BTreeMap;
 use std::fs::File;
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
-use tokio::io::AsyncWriteExt;
+use std::sync::Arc;
+use std::time::Instant;
+
+// Which part of the build to run; the default is the whole thing
+#[derive(Debug, Default, Clone)]
+pub struct BuildOptions {
+    pub from_phase: Option<Phase>,
+    pub until_phase: Option<Phase>,
+    // Build in a fresh timestamped work dir that later builds leave alone
+    pub keep_build: bool,
+}
+
+// One installable tree produced by a build
+pub struct PackageArtifact {
+    pub name: PackageName,
+    pub dir: PathBuf,
+    pub sonames: SonameScan,
+}
+
+// Result of a build: `root` holds one tree per package (none for partial builds)
+pub struct BuildArtifact {
+    pub root: PathBuf,
+    pub packages: Vec<PackageArtifact>,
+    // Archived copies of build.log and check.log, by kind
+    pub logs: Vec<(&'static str, PathBuf)>,
+    // The test suite failed but the recipe marks it optional
+    pub check_failed: bool,
+}
+
+impl BuildArtifact {
+    pub fn package(&self, name: &PackageName) -> Option<&PackageArtifact> {
+        self.packages.iter().find(|p| p.name == *name)
+    }
+
+    // Copy every package tree to `dest/<package>/`, replacing earlier copies
+    pub fn export(&self, dest: &Path) -> Result<Vec<PathBuf>, RavenError> {
+        let mut exported = Vec::new();
+        for pkg in &self.packages {
+            let target = dest.join(&pkg.name.0);
+            if target.exists() {
+                std::fs::remove_dir_all(&target)?;
+            }
+            std::fs::create_dir_all(&target)?;
+
+            let status = std::process::Command::new("cp")
+                .arg("-a")
+                .arg(format!("{}/.", pkg.dir.display()))
+                .arg(format!("{}/", target.display()))
+                .status()?;
+            if !status.success() {
+                return Err(RavenError::IoError(std::io::Error::other(format!(
+                    "Failed to copy {} to {}",
+                    pkg.name.0,
+                    target.display()
+                ))));
+            }
+            exported.push(target);
+        }
+        Ok(exported)
+    }
+}
 
 pub struct Builder {
     work_dir: PathBuf,
+    config: RavenConfig,
+    // Shared by concurrent downloads so their bars stack instead of clobbering
+    progress: MultiProgress,
+    fetchers: FetcherRegistry,
 }
 
 impl Builder {
-    pub fn new(work_dir: PathBuf) -> Self {
-        Self { work_dir }
+    pub fn new(work_dir: PathBuf, config: RavenConfig) -> Self {
+        let progress = MultiProgress::new();
+
+        let mut fetchers = FetcherRegistry::new();
+        let http: Arc<dyn Fetcher> =
+            Arc::new(HttpFetcher::new(config.network.clone(), progress.clone()));
+        fetchers.register("http", http.clone());
+        fetchers.register("https", http);
+        fetchers.register("file", Arc::new(FileFetcher));
+        fetchers.register("path", Arc::new(FileFetcher));
+        fetchers.register(
+            "git",
+            Arc::new(GitFetcher::new(
+                config.cache_dir.join("git"),
+                config.offline,
+                progress.clone(),
+            )),
+        );
+        for (scheme, command) in &config.fetchers {
+            fetchers.register(scheme, Arc::new(CommandFetcher::new(command.clone())));
+        }
+
+        Self {
+            work_dir,
+            config,
+            progress,
+            fetchers,
+        }
+    }
+
+    pub fn config(&self) -> &RavenConfig {
+        &self.config
     }
 
-    pub async fn build(&self, recipe: &Recipe) -> Result<PathBuf, RavenError> {
-        let pkg_dir = self.work_dir.join(format!("{}-build", recipe.name.0));
+    pub fn max_connections(&self) -> usize {
+        self.config.network.max_connections.max(1)
+    }
+
+    fn target_profile(&self, recipe: &Recipe) -> Result<Option<&TargetProfile>, RavenError> {
+        let arch = recipe
+            .target_arch
+            .as_deref()
+            .unwrap_or(std::env::consts::ARCH);
+        self.config.target_profile(arch)
+    }
+
+    // The sandbox environment, layered: fixed base, [build] config, target
+    // profile, then the recipe's own `[env]` adjustments
+    fn build_env(
+        &self,
+        recipe: &Recipe,
+        target: Option<&TargetProfile>,
+        epoch: i64,
+    ) -> BTreeMap<String, String> {
+        let build = &self.config.build;
+        let mut env: BTreeMap<String, String> = [
+            (
+                "PATH",
+                "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+            ),
+            ("HOME", "/tmp"),
+            ("SHELL", "/bin/sh"),
+            ("LC_ALL", "C"),
+            ("TZ", "UTC"),
+            ("DESTDIR", "/out"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        env.insert("SOURCE_DATE_EPOCH".into(), epoch.to_string());
+        env.insert("CFLAGS".into(), build.cflags.clone());
+        env.insert("CXXFLAGS".into(), build.cxxflags.clone());
+        env.insert("LDFLAGS".into(), build.ldflags.clone());
+        env.insert(
+            "MAKEFLAGS".into(),
+            build
+                .makeflags
+                .clone()
+                .unwrap_or_else(|| format!("-j{}", build.jobs())),
+        );
+        env.extend(build.env.clone());
+
+        let append = |env: &mut BTreeMap<String, String>, key: &str, value: &str| {
+            let current = env.remove(key).unwrap_or_default();
+            let joined = format!("{} {}", current, value).trim().to_string();
+            env.insert(key.to_string(), joined);
+        };
+
+        for (key, value) in target.map(|t| t.env()).unwrap_or_default() {
+            if matches!(key, "CFLAGS" | "CXXFLAGS" | "LDFLAGS") {
+                append(&mut env, key, &value);
+            } else {
+                env.insert(key.to_string(), value);
+            }
+        }
+
+        for (key, value) in &recipe.env.append {
+            append(&mut env, key, value);
+        }
+        env.extend(recipe.env.set.clone());
+        env
+    }
+
+    // `<name>-build` is reused (and wiped) by every build of the recipe;
+    // kept builds get a directory of their own
+    fn build_dir(&self, recipe: &Recipe, opts: &BuildOptions) -> PathBuf {
+        if !opts.keep_build {
+            return self.work_dir.join(format!("{}-build", recipe.name.0));
+        }
+        let stamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.work_dir
+            .join(format!("{}-build-{}", recipe.name.0, stamp))
+    }
+
+    // Newest of `<name>-build` and the kept `<name>-build-<ts>` trees
+    fn latest_build_dir(&self, recipe: &Recipe) -> Result<Option<PathBuf>, RavenError> {
+        let prefix = format!("{}-build", recipe.name.0);
+        let entries = match std::fs::read_dir(&self.work_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut latest = None;
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let ours = match name.strip_prefix(&prefix) {
+                Some("") => true,
+                Some(rest) => rest
+                    .strip_prefix('-')
+                    .is_some_and(|ts| !ts.is_empty() && ts.bytes().all(|b| b.is_ascii_digit())),
+                None => false,
+            };
+            if !ours || !entry.path().join("src").is_dir() {
+                continue;
+            }
+            let modified = entry.metadata()?.modified()?;
+            if latest.as_ref().is_none_or(|(time, _)| modified > *time) {
+                latest = Some((modified, entry.path()));
+            }
+        }
+        Ok(latest.map(|(_, path)| path))
+    }
+
+    fn distfiles_dir(&self) -> PathBuf {
+        self.config.cache_dir.join("distfiles")
+    }
+
+    // Run the build and archive its logs, whether the phases succeed or not
+    pub async fn build(
+        &self,
+        recipe: &Recipe,
+        opts: &BuildOptions,
+    ) -> Result<BuildArtifact, RavenError> {
+        let pkg_dir = self.build_dir(recipe, opts);
+        match self.build_in(recipe, opts, &pkg_dir).await {
+            Ok(mut artifact) => {
+                artifact.logs = self.archive_logs(recipe, &pkg_dir).await?;
+                Ok(artifact)
+            }
+            Err(RavenError::BuildFailed {
+                message, log, tail, ..
+            }) => {
+                let archived = self
+                    .archive_logs(recipe, &pkg_dir)
+                    .await
+                    .unwrap_or_default();
+                Err(RavenError::BuildFailed {
+                    message,
+                    log,
+                    tail,
+                    archived,
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // `<kind>.log` of the work dir becomes `<name>-<version>[-<kind>]-<ts>.log.gz`
+    async fn archive_logs(
+        &self,
+        recipe: &Recipe,
+        pkg_dir: &Path,
+    ) -> Result<Vec<(&'static str, PathBuf)>, RavenError> {
+        let dir = self.config.logs.dir.clone();
+        let base = format!("{}-{}", recipe.name.0, recipe.version);
+        let pkg_dir = pkg_dir.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let timestamp = buildlog::now();
+            let mut archived = Vec::new();
+            for kind in buildlog::KINDS {
+                let log = pkg_dir.join(format!("{}.log", kind));
+                if !log.exists() {
+                    continue;
+                }
+                let stem = match kind {
+                    "build" => base.clone(),
+                    _ => format!("{}-{}", base, kind),
+                };
+                archived.push((kind, buildlog::archive(&log, &dir, &stem, timestamp)?));
+            }
+            Ok(archived)
+        })
+        .await
+        .map_err(|e| RavenError::IoError(std::io::Error::other(e)))?
+    }
+
+    async fn build_in(
+        &self,
+        recipe: &Recipe,
+        opts: &BuildOptions,
+        pkg_dir: &Path,
+    ) -> Result<BuildArtifact, RavenError> {
+        let pkg_dir = pkg_dir.to_path_buf();
+        let src_dir = pkg_dir.join("src");
+        let out_dir = pkg_dir.join("out");
+
+        let first = opts.from_phase.unwrap_or(Phase::Prepare);
+        let last = opts.until_phase.unwrap_or(Phase::Install);
+
+        // Later phases reuse the tree left by a previous run
+        let patch_names = if first == Phase::Prepare {
+            self.prepare_tree(recipe, &pkg_dir).await?
+        } else if src_dir.exists() {
+            Vec::new()
+        } else {
+            return Err(RavenError::BuildError(format!(
+                "No previous build tree for {} at {}; run without --from-phase first",
+                recipe.name.0,
+                pkg_dir.display()
+            )));
+        };
+
+        // Prepare Sandbox Script
+        let target = self.target_profile(recipe)?;
+        let epoch = source_date_epoch(recipe, &pkg_dir)?;
+        let work_dir = source_dir(recipe, &src_dir)?;
+        let prelude = format!("set -e\numask 022\ncd {}\n", shell_quote(&work_dir));
+
+        let patch_script: String = recipe
+            .patches
+            .iter()
+            .zip(&patch_names)
+            .map(|(p, name)| format!("patch -p{} -i /patches/{}\n", p.strip, name))
+            .collect();
+
+        let sandbox = sandbox(&pkg_dir, target, self.build_env(recipe, target, epoch));
+        let log_path = pkg_dir.join("build.log");
+        let check_path = pkg_dir.join("check.log");
+        if check_path.exists() {
+            std::fs::remove_file(&check_path)?;
+        }
+        let mut log = if first == Phase::Prepare {
+            File::create(&log_path)?
+        } else {
+            std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&log_path)?
+        };
+
+        let spinner_build = create_spinner(&format!("Compiling {}...", recipe.name.0));
+        let mut check_failed = false;
+
+        for phase in Phase::ALL.into_iter().filter(|p| *p >= first && *p <= last) {
+            let mut commands = recipe.phase_commands(phase).join("\n");
+            if phase == Phase::Prepare {
+                commands = format!("{}{}", patch_script, commands);
+            }
+            if commands.trim().is_empty() {
+                continue;
+            }
+
+            if phase == Phase::Check && !self.config.build.check {
+                writeln!(log, "\n==> [{}] check skipped (--no-check)", recipe.name.0)?;
+                continue;
+            }
+
+            spinner_build.set_message(format!("{}: {}...", recipe.name.0, phase));
+
+            // Test suites are noisy; they get a log of their own
+            let (output, output_path) = if phase == Phase::Check {
+                writeln!(log, "\n==> [{}] check (output in check.log)", recipe.name.0)?;
+                (File::create(&check_path)?, &check_path)
+            } else {
+                writeln!(log, "\n==> [{}] {}", recipe.name.0, phase)?;
+                (log.try_clone()?, &log_path)
+            };
+
+            // EXECUTE SANDBOX
+            let started = Instant::now();
+            let result = sandbox.run(&format!("{}{}\n", prelude, commands), output);
+            let elapsed = started.elapsed().as_secs_f32();
+
+            if let Err(RavenError::BuildError(reason)) = result {
+                writeln!(
+                    log,
+                    "==> [{}] {} FAILED after {:.1}s",
+                    recipe.name.0, phase, elapsed
+                )?;
+                if phase == Phase::Check && recipe.check_optional {
+                    check_failed = true;
+                    spinner_build.suspend(|| {
+                        eprintln!(
+                            "Warning: test suite of {} failed ({}); continuing because check_optional is set",
+                            recipe.name.0, reason
+                        )
+                    });
+                    continue;
+                }
+                spinner_build.finish_and_clear();
+                return Err(RavenError::BuildFailed {
+                    message: format!("{} phase of {} failed: {}", phase, recipe.name.0, reason),
+                    tail: log_tail(output_path, self.config.build.log_tail_lines),
+                    log: output_path.clone(),
+                    archived: Vec::new(),
+                });
+            }
+            result?;
+
+            writeln!(
+                log,
+                "==> [{}] {} finished in {:.1}s",
+                recipe.name.0, phase, elapsed
+            )?;
+            spinner_build
+                .suspend(|| println!("   ➜ {} {} ({:.1}s)", recipe.name.0, phase, elapsed));
+        }
+
+        spinner_build.finish_and_clear();
+
+        let mut artifact = BuildArtifact {
+            root: out_dir,
+            packages: Vec::new(),
+            logs: Vec::new(),
+            check_failed,
+        };
+        if last == Phase::Install {
+            let (root, packages) = match self.finish_artifact(recipe, target, &pkg_dir, epoch).await
+            {
+                Ok(finished) => finished,
+                Err(e) => {
+                    writeln!(log, "==> [{}] post-processing FAILED: {}", recipe.name.0, e)?;
+                    return Err(RavenError::BuildFailed {
+                        message: format!("post-processing of {} failed: {}", recipe.name.0, e),
+                        tail: log_tail(&log_path, self.config.build.log_tail_lines),
+                        log: log_path,
+                        archived: Vec::new(),
+                    });
+                }
+            };
+            artifact.root = root;
+            artifact.packages = packages;
+        }
+        log_success(&format!("Build complete: {}", recipe.name.0));
+
+        Ok(artifact)
+    }
+
+    // Interactive shell in the sandbox of a build tree of the recipe: `dir`,
+    // or else the most recent one (kept builds included). Without any, a
+    // fresh tree is set up by running the prepare phase.
+    pub async fn shell(&self, recipe: &Recipe, dir: Option<&Path>) -> Result<(), RavenError> {
+        let pkg_dir = match dir {
+            Some(dir) if dir.join("src").is_dir() => dir.to_path_buf(),
+            Some(dir) => {
+                return Err(RavenError::BuildError(format!(
+                    "No build tree of {} at {}",
+                    recipe.name.0,
+                    dir.display()
+                )))
+            }
+            None => match self.latest_build_dir(recipe)? {
+                Some(dir) => dir,
+                None => {
+                    let opts = BuildOptions {
+                        until_phase: Some(Phase::Prepare),
+                        ..Default::default()
+                    };
+                    let pkg_dir = self.build_dir(recipe, &opts);
+                    self.build_in(recipe, &opts, &pkg_dir).await?;
+                    pkg_dir
+                }
+            },
+        };
+
+        let target = self.target_profile(recipe)?;
+        let epoch = source_date_epoch(recipe, &pkg_dir)?;
+        let work_dir = source_dir(recipe, &pkg_dir.join("src"))?;
+
+        let mut env = self.build_env(recipe, target, epoch);
+        env.insert("PS1".into(), format!("(raven:{}) \\w \\$ ", recipe.name.0));
+        let sandbox = sandbox(&pkg_dir, target, env);
+
+        log_success(&format!(
+            "Entering the build sandbox of {} at {} (exit to leave)",
+            recipe.name.0,
+            pkg_dir.display()
+        ));
+        sandbox.interactive(&work_dir)
+    }
+
+    // Post-process the installed tree, then normalize it for reproducibility
+    async fn finish_artifact(
+        &self,
+        recipe: &Recipe,
+        target: Option<&TargetProfile>,
+        pkg_dir: &Path,
+        epoch: i64,
+    ) -> Result<(PathBuf, Vec<PackageArtifact>), RavenError> {
+        let out_dir = pkg_dir.join("out");
+        let dbg_dir = pkg_dir.join("dbg");
+        if dbg_dir.exists() {
+            tokio::fs::remove_dir_all(&dbg_dir).await?;
+        }
+
+        let processor = PostProcessor::new(
+            self.config.post_process.for_recipe(&recipe.post_process),
+            target,
+        );
+        let root = pkg_dir.join("pkgs");
+        let split_recipe = recipe.clone();
+        let (out, dbg, pkgs) = (out_dir, dbg_dir, root.clone());
+        let (report, packages) = tokio::task::spawn_blocking(move || {
+            let report = processor.run(&out, &dbg)?;
+            let trees = split::split(&split_recipe, &out, &dbg, &pkgs)?;
+            manifest::normalize_tree(&pkgs, epoch)?;
+
+            let mut packages = Vec::new();
+            for (name, dir) in trees {
+                let sonames = SonameScan::scan(&dir)?;
+                packages.push(PackageArtifact { name, dir, sonames });
+            }
+            Ok::<_, RavenError>((report, packages))
+        })
+        .await
+        .map_err(|e| RavenError::IoError(std::io::Error::other(e)))??;
+
+        println!(
+            "   ➜ {} post-processed: {} stripped, {} compressed, {} removed",
+            recipe.name.0, report.stripped, report.compressed, report.removed
+        );
+
+        Ok((root, packages))
+    }
+
+    // Fresh work tree: sandbox skeleton, verified sources unpacked into src/,
+    // patches staged. Returns the staged patch names in application order.
+    async fn prepare_tree(
+        &self,
+        recipe: &Recipe,
+        pkg_dir: &Path,
+    ) -> Result<Vec<String>, RavenError> {
         let src_dir = pkg_dir.join("src");
         let out_dir = pkg_dir.join("out");
 
@@ -40,138 +571,412 @@ impl Builder {
         }
 
         // QEMU (Cross-Compilation support)
-        let current_arch = std::env::consts::ARCH;
-        let target_arch = recipe.target_arch.as_deref().unwrap_or(current_arch);
-        let needs_qemu = target_arch != current_arch;
-
-        if needs_qemu {
-            let qemu_bin = "/usr/bin/qemu-aarch64-static";
-            let dest = pkg_dir.join("usr/bin/qemu-aarch64-static");
-            if Path::new(qemu_bin).exists() {
+        if let Some(qemu_bin) = self.target_profile(recipe)?.and_then(|t| t.qemu.as_ref()) {
+            // Only needed when the build runs target binaries, so not fatal
+            if !qemu_bin.exists() {
+                eprintln!(
+                    "Warning: emulator {} not found; target binaries cannot run during the build",
+                    qemu_bin.display()
+                );
+            } else {
+                let dest = pkg_dir.join(qemu_bin.strip_prefix("/").unwrap_or(qemu_bin));
                 tokio::fs::create_dir_all(dest.parent().unwrap()).await?;
                 tokio::fs::copy(qemu_bin, dest).await?;
             }
         }
         spinner.finish_and_clear();
 
-        // Download with Retry & Progress Bar
-        let tarball = pkg_dir.join("source.tar");
-        self.download_with_retry(
-            &recipe.source_url,
-            &tarball,
-            &recipe.sha256_sum.0,
-            &recipe.name.0,
-        )
-        .await?;
-
-        let spinner_build = create_spinner(&format!(
-            "Compiling {} (this may take a while)...",
-            recipe.name.0
-        ));
+        // The configured signature policy applies whatever the recipe says
+        let sources = recipe.all_sources();
+        let policy = &self.config.signatures;
+        let trusted = policy.trusted_keys(&recipe.name.0, &recipe.trusted_keys);
+        for source in &sources {
+            let unsigned = source.signature_url.is_none() && !GitSource::is_git(&source.url);
+            if unsigned && policy.required(&recipe.name.0) {
+                return Err(RavenError::SignatureError(format!(
+                    "{}: source {} has no signature_url, but config.toml requires signed sources for this package",
+                    recipe.name.0, source.url
+                )));
+            }
+            if source.signature_url.is_some() && trusted.is_empty() {
+                return Err(RavenError::SignatureError(format!(
+                    "{}: source {} is signed but no trusted keys are configured or listed in the recipe",
+                    recipe.name.0, source.url
+                )));
+            }
+        }
 
-        // Unpack
-        let tar_clone = tarball.clone();
-        let src_clone = src_dir.clone();
-        tokio::task::spawn_blocking(move || {
-            let f = File::open(tar_clone).unwrap();
-            let mut ar = tar::Archive::new(flate2::read::GzDecoder::new(f));
-            ar.unpack(src_clone).unwrap();
-        })
-        .await
-        .map_err(|e| RavenError::IoError(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+        // Download (or reuse from the distfiles cache)
+        let downloads = self.fetch_sources(recipe).await?;
 
-        // Prepare Sandbox Script
-        let mut cross_env = String::new();
-        if needs_qemu {
-            cross_env = format!(
-                "export CC=aarch64-linux-gnu-gcc\nexport CXX=aarch64-linux-gnu-g++\nexport CROSS_COMPILE=aarch64-linux-gnu-\n"
-            );
-        }
-
-        let script = format!(
-            "{}\nexport DESTDIR=/out\ncd /src\nDIR=$(ls -d */ | head -n 1)\nif [ -n \"$DIR\" ]; then cd \"$DIR\"; fi\n{}\n{}", 
-            cross_env,
-            recipe.build_commands.join("\n"),
-            recipe.install_commands.join("\n")
-        );
+        // Upstream signatures must check out before anything is unpacked
+        let keyring = Keyring::new(self.config.keyring_dir.clone());
+        for (source, download) in sources.iter().zip(&downloads) {
+            if source.signature_url.is_some() {
+                let signer = keyring.verify(&signature_path(download), download, trusted)?;
+                log_success(&format!(
+                    "Good signature on {} from {}",
+                    archive::file_name_from_url(&source.url),
+                    signer
+                ));
+            }
+        }
 
-        let sandbox = ScriptSandbox::new(&pkg_dir);
-        let log = File::create(pkg_dir.join("build.log"))?;
+        // Verify patches before anything is unpacked
+        let patch_names = self.stage_patches(recipe, &pkg_dir.join("patches")).await?;
 
-        // EXECUTE SANDBOX
-        sandbox.run(&script, log)?;
+        // Unpack in declaration order (or stage single-file sources untouched)
+        let mut newest = None;
+        let mut staged = Vec::new();
+        for (source, download) in sources.iter().zip(downloads) {
+            let dest = match &source.dest {
+                Some(d) => src_dir.join(d),
+                None => src_dir.clone(),
+            };
+            tokio::fs::create_dir_all(&dest).await?;
 
-        spinner_build.finish_and_clear();
-        log_success(&format!("Build complete: {}", recipe.name.0));
+            if source.extract {
+                let format = ArchiveFormat::detect(&download, &source.url)?;
+                let mtime = tokio::task::spawn_blocking(move || {
+                    archive::extract(&download, &dest, format, ExtractLimits::default())
+                })
+                .await
+                .map_err(|e| {
+                    RavenError::ArchiveError(format!("Extraction task failed: {}", e))
+                })??;
+                newest = newest.max(mtime);
+            } else {
+                let staged_path = dest.join(archive::file_name_from_url(&source.url));
+                tokio::fs::copy(&download, &staged_path).await?;
+                staged.push(staged_path);
+            }
+        }
+
+        // Pinned now so that later phases (and --from-phase runs) agree on it.
+        // Taken from what the archives record (git exports carry the commit
+        // time), never from the clock, so that rebuilds get the same value.
+        let epoch = recipe.source_date_epoch.or(newest).unwrap_or(0);
+        tokio::fs::write(pkg_dir.join(EPOCH_FILE), epoch.to_string()).await?;
+        // Single files record no time of their own
+        for path in staged {
+            manifest::set_mtime(&path, epoch)?;
+        }
+
+        Ok(patch_names)
+    }
 
-        Ok(out_dir)
+    // Make every source of the recipe available in the distfiles cache and
+    // return the cached paths in declaration order. Cached files are re-hashed
+    // before use; the network is only touched for missing or corrupt entries.
+    pub async fn fetch_sources(&self, recipe: &Recipe) -> Result<Vec<PathBuf>, RavenError> {
+        let mut paths = Vec::new();
+        for source in recipe.all_sources() {
+            paths.push(self.fetch_source(recipe, &source).await?);
+        }
+        Ok(paths)
     }
 
-    // Robust download logic
-    async fn download_with_retry(
+    // Cached source file plus its detached signature (stored as `<file>.sig`)
+    pub async fn fetch_source(
         &self,
-        url: &str,
-        path: &Path,
-        hash: &str,
-        pkg_name: &str,
-    ) -> Result<(), RavenError> {
-        let max_retries = 3;
-        let mut attempt = 0;
-
-        loop {
-            attempt += 1;
-            match self.download_inner(url, path, hash, pkg_name).await {
-                Ok(_) => return Ok(()),
-                Err(e) => {
-                    if attempt >= max_retries {
-                        return Err(e);
+        recipe: &Recipe,
+        source: &Source,
+    ) -> Result<PathBuf, RavenError> {
+        let cached = self.fetch_source_file(recipe, source).await?;
+
+        if let Some(sig_url) = &source.signature_url {
+            let sig = signature_path(&cached);
+            if !sig.exists() {
+                let sig_source = Source {
+                    url: sig_url.clone(),
+                    sha256: None,
+                    checksums: Checksums::default(),
+                    dest: None,
+                    extract: false,
+                    mirrors: Vec::new(),
+                    signature_url: None,
+                };
+                let part = sig.with_extension("sig.part");
+                self.download_from_mirrors(recipe, &sig_source, &part, &Checksums::default())
+                    .await?;
+                tokio::fs::rename(&part, &sig).await?;
+            }
+        }
+        Ok(cached)
+    }
+
+    async fn fetch_source_file(
+        &self,
+        recipe: &Recipe,
+        source: &Source,
+    ) -> Result<PathBuf, RavenError> {
+        let distfiles = self.distfiles_dir();
+        tokio::fs::create_dir_all(&distfiles).await?;
+
+        // Git sources are cached as their deterministic export, keyed by commit.
+        // The export's digest is recorded beside it; a cached tarball that
+        // doesn't match (or predates the record) is exported again.
+        let is_git = GitSource::is_git(&source.url);
+        let (checksums, cached) = if is_git {
+            let git = GitSource::parse(&source.url)?;
+            let cached = distfiles.join(format!("git-{}-{}.tar", git.repo_name(), git.commit));
+            if cached.exists() {
+                if let Ok(digest) = tokio::fs::read_to_string(cached.with_extension("sha256")).await
+                {
+                    let mut recorded = Checksums::default();
+                    recorded.insert("sha256", digest.trim());
+                    if verify_file(&cached, &recorded).await.is_ok() {
+                        return Ok(cached);
                     }
-                    tokio::time::sleep(Duration::from_secs(attempt)).await;
                 }
+                eprintln!(
+                    "Warning: cached export {} does not match its recorded digest; exporting again",
+                    cached.display()
+                );
+                tokio::fs::remove_file(&cached).await?;
+            }
+            (Checksums::default(), cached)
+        } else {
+            let checksums = source.checksums();
+            let cached = distfiles.join(checksums.key());
+            if cached.exists() && verify_file(&cached, &checksums).await.is_ok() {
+                return Ok(cached);
             }
+            (checksums, cached)
+        };
+
+        let part = cached.with_extension("part");
+        let mirror = self
+            .download_from_mirrors(recipe, source, &part, &checksums)
+            .await?;
+        if is_git {
+            let mut wanted = Checksums::default();
+            wanted.insert("sha256", "");
+            let digest = hash_prefix(&part, &wanted).await?.finalize();
+            tokio::fs::write(cached.with_extension("sha256"), &digest["sha256"]).await?;
         }
+        tokio::fs::rename(&part, &cached).await?;
+        // Remember where the file actually came from
+        tokio::fs::write(cached.with_extension("origin"), &mirror).await?;
+        Ok(cached)
     }
 
-    async fn download_inner(
+    // Copy recipe patches into the sandbox (as /patches), verifying any pinned hashes.
+    // Returns the staged file names in application order.
+    async fn stage_patches(
         &self,
-        url: &str,
-        path: &Path,
-        hash: &str,
-        pkg_name: &str,
-    ) -> Result<(), RavenError> {
-        let client = reqwest::Client::builder()
-            .user_agent("RavenPackageManager/1.0 (MissionCritical)")
-            .build()
-            .map_err(RavenError::NetworkError)?;
+        recipe: &Recipe,
+        patches_dir: &Path,
+    ) -> Result<Vec<String>, RavenError> {
+        let mut names = Vec::new();
+        if recipe.patches.is_empty() {
+            return Ok(names);
+        }
+        tokio::fs::create_dir_all(patches_dir).await?;
+
+        for (i, patch) in recipe.patches.iter().enumerate() {
+            let path = recipe.recipe_dir.join(&patch.file);
+            let content = tokio::fs::read(&path).await.map_err(|e| {
+                RavenError::InvalidRecipe(format!("Cannot read patch {}: {}", path.display(), e))
+            })?;
 
-        let mut resp = client.get(url).send().await?;
+            let expected = patch.checksums();
+            let mut hasher = MultiHasher::new(&expected);
+            hasher.update(&content);
+            expected.verify(&hasher.finalize())?;
 
-        if !resp.status().is_success() {
-            return Err(RavenError::NetworkError(
-                resp.error_for_status().unwrap_err(),
-            ));
+            let name = format!("{:02}-{}", i, archive::file_name_from_url(&patch.file));
+            tokio::fs::write(patches_dir.join(&name), content).await?;
+            names.push(name);
         }
+        Ok(names)
+    }
+
+    // Fail over between the source URL, its mirrors and configured rewrites.
+    // Returns the URL that delivered a file with the expected hash.
+    async fn download_from_mirrors(
+        &self,
+        recipe: &Recipe,
+        source: &Source,
+        path: &Path,
+        expected: &Checksums,
+    ) -> Result<String, RavenError> {
+        let pkg_name = &recipe.name.0;
+        let primary = resolve_local_path(&source.url, &recipe.recipe_dir);
+        let mut urls = vec![source.url.clone()];
+        urls.extend(source.mirrors.iter().cloned());
 
-        let total_size = resp.content_length().unwrap_or(0);
-        let pb = create_download_bar(total_size, pkg_name);
+        let mut last_err = None;
+        for url in self.config.mirror_candidates(&urls) {
+            let url = resolve_local_path(&url, &recipe.recipe_dir);
+            let fetcher = self.fetchers.for_url(&url)?;
 
-        let mut file = tokio::fs::File::create(path).await?;
-        let mut hasher = Sha256::new();
-        let mut downloaded: u64 = 0;
+            if self.config.offline && !fetcher.works_offline(&url) {
+                last_err = Some(RavenError::Offline(format!(
+                    "{} is not in the distfiles cache",
+                    url
+                )));
+                continue;
+            }
 
-        while let Some(chunk) = resp.chunk().await? {
-            file.write_all(&chunk).await?;
-            hasher.update(&chunk);
-            downloaded += chunk.len() as u64;
-            pb.set_position(downloaded);
+            match fetcher.fetch(&url, path, expected, pkg_name).await {
+                Ok(()) => {
+                    if url != primary {
+                        self.progress.suspend(|| {
+                            log_success(&format!("Fetched {} from mirror {}", pkg_name, url))
+                        });
+                    }
+                    return Ok(url);
+                }
+                Err(e) => {
+                    self.progress
+                        .suspend(|| log_error(&format!("{} failed: {}", url, e)));
+                    last_err = Some(e);
+                }
+            }
         }
 
-        pb.finish_with_message("Download complete");
+        Err(last_err.unwrap_or_else(|| {
+            RavenError::InvalidRecipe(format!("{}: source has no URLs", pkg_name))
+        }))
+    }
+}
+
+// The jail every phase (and `raven shell`) runs in
+fn sandbox(
+    pkg_dir: &Path,
+    target: Option<&TargetProfile>,
+    env: BTreeMap<String, String>,
+) -> ScriptSandbox {
+    let mut sandbox = ScriptSandbox::new(pkg_dir);
+    sandbox.set_env(env);
+    if let Some(sysroot) = target.and_then(|t| t.sysroot.as_ref()) {
+        sandbox.add_bind(sysroot);
+    }
+    sandbox
+}
+
+const EPOCH_FILE: &str = "source_date_epoch";
+
+fn source_date_epoch(recipe: &Recipe, pkg_dir: &Path) -> Result<i64, RavenError> {
+    if let Some(epoch) = recipe.source_date_epoch {
+        return Ok(epoch);
+    }
+    let recorded = std::fs::read_to_string(pkg_dir.join(EPOCH_FILE))?;
+    recorded.trim().parse().map_err(|_| {
+        RavenError::BuildError(format!("Corrupt {} in {}", EPOCH_FILE, pkg_dir.display()))
+    })
+}
+
+// Sandbox path the phases run in: `source_dir` if set, else the only
+// top-level directory under src/, else /src itself
+fn source_dir(recipe: &Recipe, src_dir: &Path) -> Result<String, RavenError> {
+    if let Some(dir) = &recipe.source_dir {
+        return Ok(format!("/src/{}", dir.trim_start_matches("./")));
+    }
 
-        let calculated_hash = hex::encode(hasher.finalize());
-        if calculated_hash != hash {
-            return Err(RavenError::HashMismatch);
+    let mut dirs = Vec::new();
+    for entry in std::fs::read_dir(src_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.file_name().to_string_lossy().to_string());
         }
-        Ok(())
+    }
+
+    Ok(match dirs.as_slice() {
+        [only] => format!("/src/{}", only),
+        _ => "/src".to_string(),
+    })
+}
+
+// Last `lines` lines of a build log, for error reports
+fn log_tail(log: &Path, lines: usize) -> String {
+    let content = std::fs::read(log).unwrap_or_default();
+    let content = String::from_utf8_lossy(&content);
+    let all: Vec<&str> = content.lines().collect();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
+fn signature_path(file: &Path) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(".sig");
+    PathBuf::from(name)
+}
+
+// Plain relative paths in recipes are relative to the recipe file
+fn resolve_local_path(url: &str, recipe_dir: &Path) -> String {
+    if scheme_of(url) == "path" && Path::new(url).is_relative() {
+        recipe_dir.join(url).to_string_lossy().to_string()
+    } else {
+        url.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    #[tokio::test]
+    async fn fetch_source_caches_local_file_by_checksum() {
+        let dir = std::env::temp_dir().join(format!("raven-fetch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("hello-1.0.tar");
+        std::fs::write(&file, b"hello").unwrap();
+        let digest = format!("{:x}", Sha256::digest(b"hello"));
+
+        let config = RavenConfig {
+            cache_dir: dir.join("cache"),
+            ..Default::default()
+        };
+        let builder = Builder::new(dir.join("work"), config);
+        let recipe: Recipe = toml::from_str(&format!(
+            r#"
+name = "hello"
+version = "1.0.0"
+description = "hello"
+dependencies = []
+sources = [
+    {{ url = "{url}", checksums = {{ sha256 = "{digest}" }} }},
+    {{ url = "file://{url}", checksums = {{ sha256 = "{wrong}" }} }},
+]
+"#,
+            url = file.display(),
+            digest = digest,
+            wrong = "0".repeat(64),
+        ))
+        .unwrap();
+
+        let cached = builder
+            .fetch_source(&recipe, &recipe.sources[0])
+            .await
+            .unwrap();
+        assert_eq!(
+            cached,
+            dir.join("cache/distfiles")
+                .join(format!("sha256-{}", digest))
+        );
+        assert_eq!(std::fs::read(&cached).unwrap(), b"hello");
+
+        // A digest mismatch leaves nothing in the cache
+        assert!(builder
+            .fetch_source(&recipe, &recipe.sources[1])
+            .await
+            .is_err());
+        let wrong = dir
+            .join("cache/distfiles")
+            .join(format!("sha256-{}", "0".repeat(64)));
+        assert!(!wrong.exists());
+
+        // Served from the cache once the original is gone
+        std::fs::remove_file(&file).unwrap();
+        let again = builder
+            .fetch_source(&recipe, &recipe.sources[0])
+            .await
+            .unwrap();
+        assert_eq!(again, cached);
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
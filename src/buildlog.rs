@@ -0,0 +1,89 @@
+use crate::core::RavenError;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+// Logs every build may leave in its work dir, by kind
+pub const KINDS: [&str; 2] = ["build", "check"];
+
+// Blocking: gzip `log` into `dir/<stem>-<timestamp>.log.gz`.
+// Two builds within the same second get a numbered suffix.
+pub fn archive(log: &Path, dir: &Path, stem: &str, timestamp: i64) -> Result<PathBuf, RavenError> {
+    std::fs::create_dir_all(dir)?;
+
+    let mut attempt = 0;
+    let (path, file) = loop {
+        let suffix = if attempt == 0 {
+            String::new()
+        } else {
+            format!(".{}", attempt)
+        };
+        let path = dir.join(format!("{}-{}{}.log.gz", stem, timestamp, suffix));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => break (path, file),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    std::io::copy(&mut File::open(log)?, &mut encoder)?;
+    encoder.finish()?;
+    Ok(path)
+}
+
+// Blocking
+pub fn read(path: &Path) -> Result<String, RavenError> {
+    let mut content = Vec::new();
+    GzDecoder::new(File::open(path)?).read_to_end(&mut content)?;
+    Ok(String::from_utf8_lossy(&content).into_owned())
+}
+
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// "YYYY-MM-DD HH:MM" in UTC, for listings
+pub fn format_utc(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86_400);
+    let secs = timestamp.rem_euclid(86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_utc_handles_epoch_leap_days_and_negatives() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00");
+        assert_eq!(format_utc(951_782_400), "2000-02-29 00:00");
+        assert_eq!(format_utc(1_709_251_199), "2024-02-29 23:59");
+        assert_eq!(format_utc(-60), "1969-12-31 23:59");
+    }
+}
@@ -0,0 +1,189 @@
+use crate::core::RavenError;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+// Supported algorithms with their hex digest length. The first one present
+// in a set of checksums names the distfiles cache entry.
+const ALGORITHMS: [(&str, usize); 3] = [("sha512", 128), ("blake3", 64), ("sha256", 64)];
+
+// `checksums = { sha512 = "...", blake3 = "..." }`; every listed digest must match
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Checksums(pub BTreeMap<String, String>);
+
+impl Checksums {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn insert(&mut self, algorithm: &str, digest: &str) {
+        self.0
+            .insert(algorithm.to_string(), digest.to_ascii_lowercase());
+    }
+
+    // Reject unknown algorithms and digests that are not hex of the right length
+    pub fn validate(&self) -> Result<(), String> {
+        for (algorithm, digest) in &self.0 {
+            let len = ALGORITHMS
+                .iter()
+                .find(|(name, _)| name == algorithm)
+                .map(|(_, len)| *len)
+                .ok_or_else(|| format!("unsupported checksum algorithm '{}'", algorithm))?;
+
+            if digest.len() != len || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!(
+                    "{} checksum must be {} hex characters, got '{}'",
+                    algorithm, len, digest
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Cache key, e.g. "sha512-<digest>"
+    pub fn key(&self) -> String {
+        ALGORITHMS
+            .iter()
+            .find_map(|(name, _)| {
+                self.0
+                    .get(*name)
+                    .map(|d| format!("{}-{}", name, d.to_ascii_lowercase()))
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn verify(&self, actual: &BTreeMap<String, String>) -> Result<(), RavenError> {
+        for (algorithm, expected) in &self.0 {
+            let got = actual.get(algorithm).cloned().unwrap_or_default();
+            if !got.eq_ignore_ascii_case(expected) {
+                return Err(RavenError::HashMismatch {
+                    algorithm: algorithm.clone(),
+                    expected: expected.clone(),
+                    actual: got,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// Computes every algorithm listed in a `Checksums` in one pass
+#[derive(Clone)]
+pub struct MultiHasher {
+    sha256: Option<Sha256>,
+    sha512: Option<Sha512>,
+    blake3: Option<Box<blake3::Hasher>>,
+}
+
+impl MultiHasher {
+    pub fn new(wanted: &Checksums) -> Self {
+        Self {
+            sha256: wanted.0.contains_key("sha256").then(Sha256::new),
+            sha512: wanted.0.contains_key("sha512").then(Sha512::new),
+            blake3: wanted
+                .0
+                .contains_key("blake3")
+                .then(|| Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        if let Some(h) = &mut self.sha256 {
+            h.update(data);
+        }
+        if let Some(h) = &mut self.sha512 {
+            h.update(data);
+        }
+        if let Some(h) = &mut self.blake3 {
+            h.update(data);
+        }
+    }
+
+    pub fn finalize(self) -> BTreeMap<String, String> {
+        let mut out = BTreeMap::new();
+        if let Some(h) = self.sha256 {
+            out.insert("sha256".to_string(), hex::encode(h.finalize()));
+        }
+        if let Some(h) = self.sha512 {
+            out.insert("sha512".to_string(), hex::encode(h.finalize()));
+        }
+        if let Some(h) = self.blake3 {
+            out.insert("blake3".to_string(), h.finalize().to_hex().to_string());
+        }
+        out
+    }
+}
+
+impl std::io::Write for MultiHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Hasher state after consuming an existing file (e.g. a partial download)
+pub async fn hash_prefix(path: &Path, wanted: &Checksums) -> Result<MultiHasher, RavenError> {
+    let path = path.to_path_buf();
+    let mut hasher = MultiHasher::new(wanted);
+    tokio::task::spawn_blocking(move || {
+        std::io::copy(&mut File::open(path)?, &mut hasher)?;
+        Ok(hasher)
+    })
+    .await
+    .map_err(|e| RavenError::IoError(std::io::Error::other(e)))?
+}
+
+pub async fn verify_file(path: &Path, expected: &Checksums) -> Result<(), RavenError> {
+    let actual = hash_prefix(path, expected).await?.finalize();
+    expected.verify(&actual)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checksums(entries: &[(&str, &str)]) -> Checksums {
+        let mut checksums = Checksums::default();
+        for (algorithm, digest) in entries {
+            checksums.insert(algorithm, digest);
+        }
+        checksums
+    }
+
+    #[test]
+    fn validate_checks_algorithm_and_digest_length() {
+        assert!(checksums(&[("sha256", &"a".repeat(64))]).validate().is_ok());
+        assert!(checksums(&[("sha512", &"A".repeat(128))])
+            .validate()
+            .is_ok());
+        assert!(checksums(&[("md5", &"a".repeat(32))]).validate().is_err());
+        assert!(checksums(&[("sha256", &"a".repeat(63))])
+            .validate()
+            .is_err());
+        assert!(checksums(&[("blake3", &"g".repeat(64))])
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn key_prefers_strongest_algorithm() {
+        let sha256 = "b".repeat(64);
+        let sha512 = "C".repeat(128);
+        assert_eq!(
+            checksums(&[("sha256", &sha256)]).key(),
+            format!("sha256-{}", sha256)
+        );
+        assert_eq!(
+            checksums(&[("sha256", &sha256), ("sha512", &sha512)]).key(),
+            format!("sha512-{}", sha512.to_ascii_lowercase())
+        );
+        assert_eq!(Checksums::default().key(), "");
+    }
+}
@@ -1,11 +1,375 @@
-use crate::core::RavenError;
+use crate::core::{PostProcessOverrides, RavenError};
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::fs;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct RavenConfig {
     pub repo_url: String,
+    // Shared source cache (distfiles/ and git/ live here)
+    pub cache_dir: PathBuf,
+    // Never touch the network; fail if a source is not cached
+    pub offline: bool,
+    // GnuPG home with the keys trusted for upstream signatures
+    pub keyring_dir: PathBuf,
+    pub signatures: SignatureConfig,
+    // URL prefix rewrites, e.g. upstream GNU -> local mirror
+    pub mirrors: Vec<MirrorRule>,
+    pub network: NetworkConfig,
+    pub build: BuildConfig,
+    pub post_process: PostProcessConfig,
+    pub sonames: SonameConfig,
+    pub logs: LogConfig,
+    pub options: OptionsConfig,
+    // Extra URL schemes served by local commands: scheme = "cmd {url} {dest}"
+    pub fetchers: HashMap<String, String>,
+    // Cross-compilation profiles keyed by recipe `target_arch`
+    pub targets: HashMap<String, TargetProfile>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct NetworkConfig {
+    // Attempts per URL on transient errors (timeouts, dropped connections,
+    // 5xx) before failing over to the next mirror
+    pub retries: u32,
+    pub connect_timeout_secs: u64,
+    // Maximum silence between two received chunks
+    pub read_timeout_secs: u64,
+    // Exponential backoff: base * 2^attempt, capped, plus random jitter
+    pub backoff_base_ms: u64,
+    pub backoff_max_ms: u64,
+    // KiB/s per download; unlimited when absent
+    pub bandwidth_limit_kib: Option<u64>,
+    // Parallel downloads during the prefetch phase
+    pub max_connections: usize,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            connect_timeout_secs: 30,
+            read_timeout_secs: 60,
+            backoff_base_ms: 1000,
+            backoff_max_ms: 60_000,
+            bandwidth_limit_kib: None,
+            max_connections: 4,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .backoff_base_ms
+            .saturating_mul(1u64 << attempt.saturating_sub(1).min(20));
+        let delay = exp.min(self.backoff_max_ms);
+
+        // Cheap jitter source; spreading retries does not need a real RNG
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64)
+            .unwrap_or(0);
+        let jitter = if delay > 1 { nanos % (delay / 2) } else { 0 };
+
+        Duration::from_millis(delay + jitter)
+    }
+}
+
+// Compiler flags and environment every build starts from
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct BuildConfig {
+    pub cflags: String,
+    pub cxxflags: String,
+    pub ldflags: String,
+    // Defaults to "-j<jobs>"
+    pub makeflags: Option<String>,
+    // Defaults to the number of CPUs
+    pub jobs: Option<usize>,
+    // Extra variables exported to every build
+    pub env: BTreeMap<String, String>,
+    // Lines of build.log shown when a phase fails
+    pub log_tail_lines: usize,
+    // Run check_commands; --no-check turns this off for one invocation
+    pub check: bool,
+}
+
+impl Default for BuildConfig {
+    fn default() -> Self {
+        Self {
+            cflags: "-O2 -pipe".to_string(),
+            cxxflags: "-O2 -pipe".to_string(),
+            ldflags: String::new(),
+            makeflags: None,
+            jobs: None,
+            env: BTreeMap::new(),
+            log_tail_lines: 25,
+            check: true,
+        }
+    }
+}
+
+impl BuildConfig {
+    pub fn jobs(&self) -> usize {
+        self.jobs.filter(|j| *j > 0).unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        })
+    }
+}
+
+// Clean-up of the installed tree before it is packaged
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PostProcessConfig {
+    pub strip: bool,
+    // Keep debug info in a separate <name>-dbg package instead of dropping it
+    pub split_debug: bool,
+    // gzip man and info pages
+    pub compress_man: bool,
+    // Remove libtool .la archives
+    pub remove_la: bool,
+    // Globs relative to the install root, e.g. "usr/share/locale/**"
+    pub purge: Vec<String>,
+}
+
+impl Default for PostProcessConfig {
+    fn default() -> Self {
+        Self {
+            strip: true,
+            split_debug: false,
+            compress_man: true,
+            remove_la: true,
+            purge: Vec::new(),
+        }
+    }
+}
+
+impl PostProcessConfig {
+    // Settings for one recipe after its `[post_process]` opt-outs
+    pub fn for_recipe(&self, overrides: &PostProcessOverrides) -> Self {
+        Self {
+            strip: overrides.strip.unwrap_or(self.strip),
+            split_debug: overrides.split_debug.unwrap_or(self.split_debug),
+            compress_man: overrides.compress_man.unwrap_or(self.compress_man),
+            remove_la: overrides.remove_la.unwrap_or(self.remove_la),
+            purge: if overrides.purge.unwrap_or(true) {
+                self.purge.clone()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SonamePolicy {
+    Off,
+    Warn,
+    Error,
+}
+
+// Archived build logs, pruned after every build
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct LogConfig {
+    pub dir: PathBuf,
+    // Logs kept per package, newest first
+    pub keep: usize,
+    // Older logs are removed regardless of `keep`
+    pub max_age_days: Option<u64>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("/var/log/raven/build"),
+            keep: 10,
+            max_age_days: None,
+        }
+    }
+}
+
+// Signature policy kept out of the recipes, so a tampered recipe repository
+// cannot switch verification off by dropping `signature_url`/`trusted_keys`
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SignatureConfig {
+    // Every downloaded (non-git) source must carry a signature
+    pub require: bool,
+    // Package -> fingerprints. These packages must be signed, and only by
+    // these keys; the recipe's `trusted_keys` are ignored for them.
+    pub pinned: HashMap<String, Vec<String>>,
+}
+
+impl SignatureConfig {
+    pub fn required(&self, package: &str) -> bool {
+        self.require || self.pinned.contains_key(package)
+    }
+
+    // Keys a signature of `package` may come from
+    pub fn trusted_keys<'a>(&'a self, package: &str, recipe_keys: &'a [String]) -> &'a [String] {
+        self.pinned
+            .get(package)
+            .map(Vec::as_slice)
+            .unwrap_or(recipe_keys)
+    }
+}
+
+// Build option choices overriding recipe defaults. `global` only touches
+// recipes that declare the option; `packages` entries must exist.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct OptionsConfig {
+    pub global: BTreeMap<String, bool>,
+    pub packages: HashMap<String, BTreeMap<String, bool>>,
+}
+
+// What to do when a built binary needs a shared library that neither the
+// package nor its dependencies provide
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SonameConfig {
+    pub policy: SonamePolicy,
+    // Sonames (globs) expected on every system, e.g. the C runtime
+    pub ignore: Vec<String>,
+}
+
+impl Default for SonameConfig {
+    fn default() -> Self {
+        let ignore = [
+            "ld-linux*.so.*",
+            "libc.so.*",
+            "libm.so.*",
+            "libdl.so.*",
+            "libpthread.so.*",
+            "librt.so.*",
+            "libresolv.so.*",
+            "libutil.so.*",
+            "libgcc_s.so.*",
+            "libstdc++.so.*",
+        ];
+        Self {
+            policy: SonamePolicy::Warn,
+            ignore: ignore.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+impl SonameConfig {
+    pub fn ignore_set(&self) -> Result<GlobSet, RavenError> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &self.ignore {
+            builder.add(Glob::new(pattern).map_err(|e| {
+                RavenError::DependencyError(format!("Invalid soname pattern '{}': {}", pattern, e))
+            })?);
+        }
+        builder
+            .build()
+            .map_err(|e| RavenError::DependencyError(format!("Invalid soname patterns: {}", e)))
+    }
+}
+
+// Toolchain for one foreign architecture. Tools default to `<triple>-gcc` etc.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct TargetProfile {
+    pub triple: String,
+    pub cc: Option<String>,
+    pub cxx: Option<String>,
+    pub ar: Option<String>,
+    pub strip: Option<String>,
+    // Bind-mounted into the sandbox at the same path
+    pub sysroot: Option<PathBuf>,
+    pub cflags: Option<String>,
+    // User-mode emulator for running target binaries during the build
+    pub qemu: Option<PathBuf>,
+}
+
+impl TargetProfile {
+    pub fn tool(&self, configured: &Option<String>, suffix: &str) -> String {
+        configured
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", self.triple, suffix))
+    }
+
+    // Variables exported to every build phase
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let mut cflags = self.cflags.clone().unwrap_or_default();
+        let mut ldflags = String::new();
+        if let Some(sysroot) = &self.sysroot {
+            let flag = format!("--sysroot={}", sysroot.display());
+            cflags = format!("{} {}", cflags, flag).trim().to_string();
+            ldflags = flag;
+        }
+
+        let mut env = vec![
+            ("CHOST", self.triple.clone()),
+            ("CROSS_COMPILE", format!("{}-", self.triple)),
+            ("CC", self.tool(&self.cc, "gcc")),
+            ("CXX", self.tool(&self.cxx, "g++")),
+            ("AR", self.tool(&self.ar, "ar")),
+            ("STRIP", self.tool(&self.strip, "strip")),
+        ];
+        if !cflags.is_empty() {
+            env.push(("CFLAGS", cflags.clone()));
+            env.push(("CXXFLAGS", cflags));
+        }
+        if !ldflags.is_empty() {
+            env.push(("LDFLAGS", ldflags));
+        }
+        if let Some(sysroot) = &self.sysroot {
+            env.push(("SYSROOT", sysroot.display().to_string()));
+        }
+        env
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MirrorRule {
+    pub prefix: String,
+    pub urls: Vec<String>,
+}
+
+impl RavenConfig {
+    // Candidate URLs for a source in the order they should be tried:
+    // every URL is preceded by its configured mirror rewrites.
+    pub fn mirror_candidates(&self, urls: &[String]) -> Vec<String> {
+        let mut candidates: Vec<String> = Vec::new();
+        for url in urls {
+            for rule in &self.mirrors {
+                if let Some(rest) = url.strip_prefix(&rule.prefix) {
+                    candidates.extend(rule.urls.iter().map(|m| format!("{}{}", m, rest)));
+                }
+            }
+            candidates.push(url.clone());
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        candidates.retain(|c| seen.insert(c.clone()));
+        candidates
+    }
+
+    // None for native builds; foreign architectures need a [targets.<arch>] profile
+    pub fn target_profile(&self, arch: &str) -> Result<Option<&TargetProfile>, RavenError> {
+        if arch == std::env::consts::ARCH {
+            return Ok(None);
+        }
+        self.targets.get(arch).map(Some).ok_or_else(|| {
+            RavenError::BuildError(format!(
+                "No cross-compilation profile for '{}'; add [targets.{}] to config.toml",
+                arch, arch
+            ))
+        })
+    }
 }
 
 // Default configuration if file is missing
@@ -13,6 +377,26 @@ impl Default for RavenConfig {
     fn default() -> Self {
         Self {
             repo_url: "https://github.com/lear94/raven-recipes.git".to_string(),
+            cache_dir: PathBuf::from("/var/cache/raven"),
+            offline: false,
+            keyring_dir: PathBuf::from("/var/lib/raven/keyring"),
+            signatures: SignatureConfig::default(),
+            mirrors: Vec::new(),
+            network: NetworkConfig::default(),
+            build: BuildConfig::default(),
+            post_process: PostProcessConfig::default(),
+            sonames: SonameConfig::default(),
+            logs: LogConfig::default(),
+            options: OptionsConfig::default(),
+            fetchers: HashMap::new(),
+            targets: HashMap::from([(
+                "aarch64".to_string(),
+                TargetProfile {
+                    triple: "aarch64-linux-gnu".to_string(),
+                    qemu: Some(PathBuf::from("/usr/bin/qemu-aarch64-static")),
+                    ..Default::default()
+                },
+            )]),
         }
     }
 }
@@ -41,10 +425,43 @@ impl ConfigManager {
     }
 
     pub async fn save(&self, config: &RavenConfig) -> Result<(), RavenError> {
-        let content = toml::to_string_pretty(config)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        let content =
+            toml::to_string_pretty(config).map_err(|e| std::io::Error::other(e.to_string()))?;
 
         fs::write(&self.path, content).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirror_candidates_precede_each_url_and_skip_duplicates() {
+        let config = RavenConfig {
+            mirrors: vec![MirrorRule {
+                prefix: "https://ftp.gnu.org/gnu/".into(),
+                urls: vec![
+                    "https://mirror.a/gnu/".into(),
+                    "https://mirror.b/gnu/".into(),
+                ],
+            }],
+            ..Default::default()
+        };
+        let urls = [
+            "https://ftp.gnu.org/gnu/make/make-4.4.tar.gz".to_string(),
+            "https://other.org/make-4.4.tar.gz".to_string(),
+            "https://mirror.a/gnu/make/make-4.4.tar.gz".to_string(),
+        ];
+        assert_eq!(
+            config.mirror_candidates(&urls),
+            [
+                "https://mirror.a/gnu/make/make-4.4.tar.gz",
+                "https://mirror.b/gnu/make/make-4.4.tar.gz",
+                "https://ftp.gnu.org/gnu/make/make-4.4.tar.gz",
+                "https://other.org/make-4.4.tar.gz",
+            ]
+        );
+    }
+}
@@ -1,6 +1,12 @@
+use crate::buildlog;
+use crate::checksum::Checksums;
+use crate::elfdeps::SonameScan;
+use crate::manifest::Manifest;
+use crate::vcs::GitSource;
 use semver::VersionReq;
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 
@@ -18,10 +24,227 @@ pub struct Recipe {
     pub description: String,
     pub target_arch: Option<String>,
     pub dependencies: Vec<String>,
-    pub source_url: String,
-    pub sha256_sum: HashSum,
+    // Single-source shorthand, equivalent to one entry in `sources`
+    #[serde(default)]
+    pub source_url: Option<String>,
+    #[serde(default)]
+    pub sha256_sum: Option<HashSum>,
+    #[serde(default)]
+    pub checksums: Checksums,
+    // Alternative URLs for `source_url`, tried in order
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    // Detached upstream signature (.sig/.asc) for `source_url`
+    #[serde(default)]
+    pub signature_url: Option<String>,
+    // Fingerprints allowed to sign this recipe's sources
+    #[serde(default)]
+    pub trusted_keys: Vec<String>,
+    // false: stage the download as-is instead of unpacking it
+    #[serde(default = "default_extract")]
+    pub extract: bool,
+    #[serde(default)]
+    pub sources: Vec<Source>,
+    #[serde(default)]
+    pub patches: Vec<Patch>,
+    // Build phases, run in this order, each as its own `set -e` script
+    #[serde(default)]
+    pub prepare_commands: Vec<String>,
+    #[serde(default)]
+    pub configure_commands: Vec<String>,
+    #[serde(default)]
     pub build_commands: Vec<String>,
+    #[serde(default)]
+    pub check_commands: Vec<String>,
+    // A failing test suite only warns instead of blocking the install
+    #[serde(default)]
+    pub check_optional: bool,
+    // Only needed while check_commands run; ignored with --no-check
+    #[serde(default)]
+    pub check_dependencies: Vec<String>,
+    #[serde(default)]
     pub install_commands: Vec<String>,
+    // Directory under /src the phases run in. Defaults to the single top-level
+    // directory of the unpacked sources, or /src itself if there is none.
+    #[serde(default)]
+    pub source_dir: Option<String>,
+    // Fixed SOURCE_DATE_EPOCH; defaults to the newest mtime in the unpacked sources
+    #[serde(default)]
+    pub source_date_epoch: Option<i64>,
+    // Further packages carved out of the build output (e.g. foo-dev)
+    #[serde(default)]
+    pub packages: Vec<SubPackage>,
+    #[serde(default)]
+    pub env: RecipeEnv,
+    #[serde(default)]
+    pub post_process: PostProcessOverrides,
+    // Custom `${...}` variables, usable alongside the built-in ones
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    // Build options and their defaults, e.g. { ssl = true }. Entries of the
+    // command and dependency lists prefixed `ssl? ` (or `!ssl? `) only apply
+    // when the option is on (off). After loading this is the effective set.
+    #[serde(default)]
+    pub options: BTreeMap<String, bool>,
+    // Directory the recipe was loaded from (patches are resolved against it)
+    #[serde(skip)]
+    pub recipe_dir: PathBuf,
+}
+
+// Adjustments to the configured build environment:
+// `append` extends a variable (e.g. CFLAGS), `set` replaces it
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RecipeEnv {
+    pub append: BTreeMap<String, String>,
+    pub set: BTreeMap<String, String>,
+}
+
+// An installable package built by another recipe, e.g. `foo-dev`. It has the
+// recipe's version, its own runtime dependencies and the files matching `files`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SubPackage {
+    pub name: PackageName,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    // Globs relative to the install root, e.g. "usr/include/**"
+    pub files: Vec<String>,
+}
+
+// Per-recipe opt-outs from the configured post-processing steps
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct PostProcessOverrides {
+    pub strip: Option<bool>,
+    // After loading this holds the effective setting
+    pub split_debug: Option<bool>,
+    pub compress_man: Option<bool>,
+    pub remove_la: Option<bool>,
+    // false: ignore the configured purge globs
+    pub purge: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Source {
+    pub url: String,
+    // Not needed for git sources, which are pinned by `#commit=`
+    #[serde(default)]
+    pub sha256: Option<HashSum>,
+    #[serde(default)]
+    pub checksums: Checksums,
+    // Directory under /src to unpack into (defaults to /src itself)
+    #[serde(default)]
+    pub dest: Option<String>,
+    #[serde(default = "default_extract")]
+    pub extract: bool,
+    // Alternative URLs serving the same file, tried in order after `url`
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    // Detached signature checked against the recipe's `trusted_keys`
+    #[serde(default)]
+    pub signature_url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Patch {
+    // Path relative to the recipe file
+    pub file: String,
+    #[serde(default)]
+    pub sha256: Option<HashSum>,
+    #[serde(default)]
+    pub checksums: Checksums,
+    #[serde(default = "default_strip")]
+    pub strip: u32,
+}
+
+impl Source {
+    // `checksums` plus the `sha256` shorthand
+    pub fn checksums(&self) -> Checksums {
+        let mut all = self.checksums.clone();
+        if let Some(h) = &self.sha256 {
+            all.insert("sha256", &h.0);
+        }
+        all
+    }
+
+    // The content identity of this source: its checksum key or, for git, the commit id
+    pub fn pin(&self) -> String {
+        match GitSource::parse(&self.url) {
+            Ok(git) => git.commit,
+            Err(_) => self.checksums().key(),
+        }
+    }
+}
+
+impl Patch {
+    pub fn checksums(&self) -> Checksums {
+        let mut all = self.checksums.clone();
+        if let Some(h) = &self.sha256 {
+            all.insert("sha256", &h.0);
+        }
+        all
+    }
+}
+
+fn default_extract() -> bool {
+    true
+}
+
+fn default_strip() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Prepare,
+    Configure,
+    Build,
+    Check,
+    Install,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 5] = [
+        Phase::Prepare,
+        Phase::Configure,
+        Phase::Build,
+        Phase::Check,
+        Phase::Install,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Prepare => "prepare",
+            Phase::Configure => "configure",
+            Phase::Build => "build",
+            Phase::Check => "check",
+            Phase::Install => "install",
+        }
+    }
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Phase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Phase::ALL
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown phase '{}' (expected prepare, configure, build, check or install)",
+                    s
+                )
+            })
+    }
 }
 
 pub struct DependencyReq {
@@ -30,31 +253,170 @@ pub struct DependencyReq {
 }
 
 impl Recipe {
+    // All sources in staging order: the `source_url` shorthand first, then `sources`
+    pub fn all_sources(&self) -> Vec<Source> {
+        let mut all = Vec::new();
+        if let Some(url) = &self.source_url {
+            all.push(Source {
+                url: url.clone(),
+                sha256: self.sha256_sum.clone(),
+                checksums: self.checksums.clone(),
+                dest: None,
+                extract: self.extract,
+                mirrors: self.mirrors.clone(),
+                signature_url: self.signature_url.clone(),
+            });
+        }
+        all.extend(self.sources.iter().cloned());
+        all
+    }
+
+    pub fn phase_commands(&self, phase: Phase) -> &[String] {
+        match phase {
+            Phase::Prepare => &self.prepare_commands,
+            Phase::Configure => &self.configure_commands,
+            Phase::Build => &self.build_commands,
+            Phase::Check => &self.check_commands,
+            Phase::Install => &self.install_commands,
+        }
+    }
+
+    // Identifies the exact upstream input this package was built from
+    pub fn source_hash(&self) -> String {
+        self.all_sources()
+            .iter()
+            .map(Source::pin)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    // "+ssl -docs", read back by parse_option_string
+    pub fn option_string(&self) -> String {
+        self.options
+            .iter()
+            .map(|(name, on)| format!("{}{}", if *on { '+' } else { '-' }, name))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // Identifies what a build's artifacts were made from: the upstream input
+    // and, for recipes that have any, the chosen options
+    pub fn build_key(&self) -> String {
+        if self.options.is_empty() {
+            self.source_hash()
+        } else {
+            format!("{};{}", self.source_hash(), self.option_string())
+        }
+    }
+
     pub fn parse_dependencies(&self) -> Result<Vec<DependencyReq>, RavenError> {
-        let mut parsed = Vec::new();
+        parse_requirements(&self.dependencies)
+    }
 
-        for dep_str in &self.dependencies {
-            let parts: Vec<&str> = dep_str.splitn(2, ' ').collect();
-            let name = parts[0];
-            let req_str = if parts.len() > 1 { parts[1] } else { "*" };
+    pub fn parse_check_dependencies(&self) -> Result<Vec<DependencyReq>, RavenError> {
+        parse_requirements(&self.check_dependencies)
+    }
 
-            let req = VersionReq::parse(req_str).map_err(|e| {
-                RavenError::DependencyError(format!("Invalid requirement for {}: {}", name, e))
-            })?;
+    // Package holding debug info split off by post-processing
+    pub fn debug_package(&self) -> PackageName {
+        PackageName(format!("{}-dbg", self.name.0))
+    }
 
-            parsed.push(DependencyReq {
-                name: PackageName(name.to_string()),
-                req,
-            });
+    fn splits_debug(&self) -> bool {
+        self.post_process.split_debug == Some(true)
+    }
+
+    // Every package this recipe can produce: itself, its sub-packages and,
+    // when debug info is split off, the debug package
+    pub fn package_names(&self) -> Vec<PackageName> {
+        let mut names = vec![self.name.clone()];
+        names.extend(self.packages.iter().map(|p| p.name.clone()));
+        if self.splits_debug() && !names.contains(&self.debug_package()) {
+            names.push(self.debug_package());
+        }
+        names
+    }
+
+    // Runtime dependencies recorded for one of this recipe's packages
+    pub fn package_dependencies(
+        &self,
+        name: &PackageName,
+    ) -> Result<Vec<DependencyReq>, RavenError> {
+        if *name == self.name {
+            return self.parse_dependencies();
         }
-        Ok(parsed)
+        if let Some(sub) = self.packages.iter().find(|p| p.name == *name) {
+            return parse_requirements(&sub.dependencies);
+        }
+        if *name == self.debug_package() && self.splits_debug() {
+            return parse_requirements(&[format!("{} ={}", self.name.0, self.version)]);
+        }
+        Err(RavenError::DependencyError(format!(
+            "{} is not a package of recipe {}",
+            name.0, self.name.0
+        )))
+    }
+}
+
+// "+ssl -docs" -> {ssl: true, docs: false}; anything else is skipped
+pub fn parse_option_string(options: &str) -> BTreeMap<String, bool> {
+    options
+        .split_whitespace()
+        .filter_map(|choice| match choice.split_at_checked(1) {
+            Some(("+", name)) if !name.is_empty() => Some((name.to_string(), true)),
+            Some(("-", name)) if !name.is_empty() => Some((name.to_string(), false)),
+            _ => None,
+        })
+        .collect()
+}
+
+// "name [semver requirement]" entries, e.g. "openssl >=3.0"
+fn parse_requirements(list: &[String]) -> Result<Vec<DependencyReq>, RavenError> {
+    let mut parsed = Vec::new();
+
+    for dep_str in list {
+        let parts: Vec<&str> = dep_str.splitn(2, ' ').collect();
+        let name = parts[0];
+        let req_str = if parts.len() > 1 { parts[1] } else { "*" };
+
+        let req = VersionReq::parse(req_str).map_err(|e| {
+            RavenError::DependencyError(format!("Invalid requirement for {}: {}", name, e))
+        })?;
+
+        parsed.push(DependencyReq {
+            name: PackageName(name.to_string()),
+            req,
+        });
     }
+    Ok(parsed)
 }
 
 #[derive(Error, Debug)]
 pub enum RavenError {
-    #[error("Checksum verification failed (Files may be corrupted or tampered)")]
-    HashMismatch,
+    #[error("{algorithm} checksum mismatch (files may be corrupted or tampered): expected {expected}, got {actual}")]
+    HashMismatch {
+        algorithm: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Source extraction failed: {0}")]
+    ArchiveError(String),
+
+    #[error("Invalid recipe: {0}")]
+    InvalidRecipe(String),
+
+    #[error("Build failed: {0}")]
+    BuildError(String),
+
+    #[error("Build failed: {message}\n{tail}\nFull log: {}", log.display())]
+    BuildFailed {
+        message: String,
+        log: PathBuf,
+        tail: String,
+        // Compressed copies (by log kind) kept after the build dir is gone
+        archived: Vec<(&'static str, PathBuf)>,
+    },
 
     #[error("Dependency resolution failed: {0}")]
     DependencyError(String),
@@ -68,16 +430,101 @@ pub enum RavenError {
     #[error("Network connection failed")]
     NetworkError(#[from] reqwest::Error),
 
+    #[error("Signature verification failed: {0}")]
+    SignatureError(String),
+
+    #[error("Source fetch failed: {0}")]
+    FetchError(String),
+
+    #[error("Offline mode: {0}")]
+    Offline(String),
+
     #[error("Git repository error: {0}")]
     GitError(#[from] git2::Error),
 
     #[error("Recipe parsing error: {0}")]
     ParseError(#[from] toml::de::Error),
 
+    #[error("Transaction error: {0}")]
+    TransactionError(String),
+
     #[error("Version Error: {0}")]
     VersionError(#[from] semver::Error),
 }
 
+// Schema upgrade for databases created by older versions
+async fn add_column_if_missing(
+    db: &SqlitePool,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> Result<(), RavenError> {
+    let (count,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?")
+            .bind(table)
+            .bind(column)
+            .fetch_one(db)
+            .await?;
+    if count == 0 {
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, decl
+        ))
+        .execute(db)
+        .await?;
+    }
+    Ok(())
+}
+
+pub struct TransactionStep {
+    pub position: i64,
+    pub package: PackageName,
+    pub version: String,
+    pub previous_version: Option<String>,
+    // Option set the package was planned with, see Recipe::option_string
+    pub options: String,
+    pub done: bool,
+}
+
+pub struct PendingTransaction {
+    pub id: i64,
+    pub steps: Vec<TransactionStep>,
+}
+
+pub struct AbortReport {
+    pub id: i64,
+    pub removed: Vec<PackageName>,
+    pub restored: Vec<(PackageName, String)>,
+    // Packages left as they are, with the reason
+    pub failed: Vec<(PackageName, String)>,
+}
+
+// What an upgrade replaced, kept until its transaction completes or is aborted
+#[derive(Serialize, Deserialize)]
+struct PackageBackup {
+    version: String,
+    hash: String,
+    manifest_hash: Option<String>,
+    options: String,
+    files: Vec<String>,
+    dependencies: Vec<String>,
+    // (soname, kind)
+    sonames: Vec<(String, String)>,
+}
+
+// id, kind, version, path, status, transaction_id, created_at
+type BuildLogRow = (i64, String, String, String, String, Option<i64>, i64);
+
+pub struct BuildLogEntry {
+    pub id: i64,
+    pub kind: String,
+    pub version: String,
+    pub path: PathBuf,
+    pub status: String,
+    pub transaction_id: Option<i64>,
+    pub created_at: i64,
+}
+
 pub struct TransactionManager {
     pub db: SqlitePool,
     pub staging_root: PathBuf,
@@ -102,11 +549,51 @@ impl TransactionManager {
                 package TEXT NOT NULL,
                 depends_on TEXT NOT NULL,
                 PRIMARY KEY (package, depends_on)
+            );
+            CREATE TABLE IF NOT EXISTS transactions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                status TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS transaction_steps (
+                transaction_id INTEGER NOT NULL,
+                position INTEGER NOT NULL,
+                package TEXT NOT NULL,
+                version TEXT NOT NULL,
+                previous_version TEXT,
+                status TEXT NOT NULL,
+                PRIMARY KEY (transaction_id, position)
+            );
+            CREATE TABLE IF NOT EXISTS package_sonames (
+                package TEXT NOT NULL,
+                soname TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                PRIMARY KEY (package, soname, kind)
+            );
+            CREATE TABLE IF NOT EXISTS build_logs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                package TEXT NOT NULL,
+                version TEXT NOT NULL,
+                path TEXT NOT NULL,
+                status TEXT NOT NULL,
+                transaction_id INTEGER,
+                created_at INTEGER NOT NULL
             );",
         )
         .execute(&db)
         .await?;
 
+        add_column_if_missing(&db, "packages", "manifest_hash", "TEXT").await?;
+        add_column_if_missing(&db, "packages", "options", "TEXT NOT NULL DEFAULT ''").await?;
+        add_column_if_missing(&db, "build_logs", "kind", "TEXT NOT NULL DEFAULT 'build'").await?;
+        add_column_if_missing(
+            &db,
+            "transaction_steps",
+            "options",
+            "TEXT NOT NULL DEFAULT ''",
+        )
+        .await?;
+
         if !staging_root.exists() {
             tokio::fs::create_dir_all(&staging_root).await?;
         }
@@ -125,16 +612,521 @@ impl TransactionManager {
         Ok(packages)
     }
 
+    // Option set each installed package was built with
+    pub async fn installed_options(&self) -> Result<Vec<(PackageName, String)>, RavenError> {
+        let rows: Vec<(String, String)> = sqlx::query_as("SELECT name, options FROM packages")
+            .fetch_all(&self.db)
+            .await?;
+        Ok(rows.into_iter().map(|(n, o)| (PackageName(n), o)).collect())
+    }
+
+    // Version, build key and artifact manifest hash recorded at install time
+    pub async fn installed_build(
+        &self,
+        name: &PackageName,
+    ) -> Result<Option<(String, String, Option<String>)>, RavenError> {
+        let row =
+            sqlx::query_as("SELECT version, hash, manifest_hash FROM packages WHERE name = ?")
+                .bind(&name.0)
+                .fetch_optional(&self.db)
+                .await?;
+        Ok(row)
+    }
+
+    // Sonames provided by the given packages and everything they depend on
+    pub async fn provided_sonames(
+        &self,
+        packages: &[PackageName],
+    ) -> Result<BTreeSet<String>, RavenError> {
+        let mut seen: BTreeSet<String> = BTreeSet::new();
+        let mut queue: Vec<String> = packages.iter().map(|p| p.0.clone()).collect();
+        let mut sonames = BTreeSet::new();
+
+        while let Some(pkg) = queue.pop() {
+            if !seen.insert(pkg.clone()) {
+                continue;
+            }
+            let provided: Vec<(String,)> = sqlx::query_as(
+                "SELECT soname FROM package_sonames WHERE package = ? AND kind = 'provides'",
+            )
+            .bind(&pkg)
+            .fetch_all(&self.db)
+            .await?;
+            sonames.extend(provided.into_iter().map(|(s,)| s));
+
+            let deps: Vec<(String,)> =
+                sqlx::query_as("SELECT depends_on FROM dependencies WHERE package = ?")
+                    .bind(&pkg)
+                    .fetch_all(&self.db)
+                    .await?;
+            queue.extend(deps.into_iter().map(|(d,)| d));
+        }
+        Ok(sonames)
+    }
+
+    // Remember an archived build (or check) log; `tx_id` links it to the
+    // transaction that triggered the build
+    pub async fn record_build_log(
+        &self,
+        package: &PackageName,
+        version: &str,
+        kind: &str,
+        path: &Path,
+        status: &str,
+        tx_id: Option<i64>,
+    ) -> Result<i64, RavenError> {
+        let id = sqlx::query(
+            "INSERT INTO build_logs (package, version, kind, path, status, transaction_id, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&package.0)
+        .bind(version)
+        .bind(kind)
+        .bind(path.to_string_lossy().to_string())
+        .bind(status)
+        .bind(tx_id)
+        .bind(buildlog::now())
+        .execute(&self.db)
+        .await?
+        .last_insert_rowid();
+        Ok(id)
+    }
+
+    // Archived logs of a package, newest first
+    pub async fn build_logs(
+        &self,
+        package: &PackageName,
+    ) -> Result<Vec<BuildLogEntry>, RavenError> {
+        let rows: Vec<BuildLogRow> = sqlx::query_as(
+            "SELECT id, kind, version, path, status, transaction_id, created_at
+             FROM build_logs WHERE package = ? ORDER BY id DESC",
+        )
+        .bind(&package.0)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(id, kind, version, path, status, transaction_id, created_at)| BuildLogEntry {
+                    id,
+                    kind,
+                    version,
+                    path: PathBuf::from(path),
+                    status,
+                    transaction_id,
+                    created_at,
+                },
+            )
+            .collect())
+    }
+
+    // Drop logs beyond the newest `keep` per package and kind or older than
+    // `max_age_days`, files included. Returns how many were removed.
+    pub async fn prune_build_logs(
+        &self,
+        keep: usize,
+        max_age_days: Option<u64>,
+    ) -> Result<usize, RavenError> {
+        let rows: Vec<(i64, String, String, String, i64)> = sqlx::query_as(
+            "SELECT id, package, kind, path, created_at FROM build_logs
+             ORDER BY package, kind, id DESC",
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        let cutoff = max_age_days.map(|days| buildlog::now() - days as i64 * 86_400);
+        let mut seen: HashMap<(String, String), usize> = HashMap::new();
+        let mut removed = 0;
+
+        for (id, package, kind, path, created_at) in rows {
+            let rank = seen.entry((package, kind)).or_insert(0);
+            *rank += 1;
+            let expired = cutoff.is_some_and(|c| created_at < c);
+            if *rank <= keep && !expired {
+                continue;
+            }
+
+            match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            sqlx::query("DELETE FROM build_logs WHERE id = ?")
+                .bind(id)
+                .execute(&self.db)
+                .await?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    // A new transaction may only start once the last one is settled
+    pub async fn ensure_no_pending(&self) -> Result<(), RavenError> {
+        match self.pending_transaction().await? {
+            Some(pending) => Err(RavenError::TransactionError(format!(
+                "Transaction #{} is incomplete. Run 'raven install --resume' or 'raven abort' first",
+                pending.id
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // Persist a build plan so it can be resumed or rolled back after a failure
+    pub async fn begin_transaction(
+        &self,
+        plan: &[(PackageName, &Recipe)],
+    ) -> Result<i64, RavenError> {
+        self.ensure_no_pending().await?;
+
+        let mut tx = self.db.begin().await?;
+
+        let created_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+
+        let id = sqlx::query("INSERT INTO transactions (status, created_at) VALUES ('running', ?)")
+            .bind(created_at)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+
+        for (position, (name, recipe)) in plan.iter().enumerate() {
+            let previous: Option<(String,)> =
+                sqlx::query_as("SELECT version FROM packages WHERE name = ?")
+                    .bind(&name.0)
+                    .fetch_optional(&mut *tx)
+                    .await?;
+
+            sqlx::query(
+                "INSERT INTO transaction_steps (transaction_id, position, package, version, previous_version, options, status)
+                 VALUES (?, ?, ?, ?, ?, ?, 'pending')",
+            )
+            .bind(id)
+            .bind(position as i64)
+            .bind(&name.0)
+            .bind(&recipe.version)
+            .bind(previous.map(|(v,)| v))
+            .bind(recipe.option_string())
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(id)
+    }
+
+    // A transaction is pending while it is still running (crash) or has failed
+    pub async fn pending_transaction(&self) -> Result<Option<PendingTransaction>, RavenError> {
+        let row: Option<(i64,)> = sqlx::query_as(
+            "SELECT id FROM transactions WHERE status IN ('running', 'failed') ORDER BY id DESC LIMIT 1",
+        )
+        .fetch_optional(&self.db)
+        .await?;
+
+        let Some((id,)) = row else {
+            return Ok(None);
+        };
+
+        let rows: Vec<(i64, String, String, Option<String>, String, String)> = sqlx::query_as(
+            "SELECT position, package, version, previous_version, options, status
+             FROM transaction_steps WHERE transaction_id = ? ORDER BY position",
+        )
+        .bind(id)
+        .fetch_all(&self.db)
+        .await?;
+
+        let steps = rows
+            .into_iter()
+            .map(
+                |(position, package, version, previous_version, options, status)| TransactionStep {
+                    position,
+                    package: PackageName(package),
+                    version,
+                    previous_version,
+                    options,
+                    done: status == "done",
+                },
+            )
+            .collect();
+
+        Ok(Some(PendingTransaction { id, steps }))
+    }
+
+    pub async fn set_step_status(
+        &self,
+        tx_id: i64,
+        step: &TransactionStep,
+        status: &str,
+    ) -> Result<(), RavenError> {
+        sqlx::query(
+            "UPDATE transaction_steps SET status = ?, version = ? WHERE transaction_id = ? AND position = ?",
+        )
+        .bind(status)
+        .bind(&step.version)
+        .bind(tx_id)
+        .bind(step.position)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_transaction_status(&self, tx_id: i64, status: &str) -> Result<(), RavenError> {
+        sqlx::query("UPDATE transactions SET status = ? WHERE id = ?")
+            .bind(status)
+            .bind(tx_id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    // Roll back a pending transaction: upgraded packages get their previous
+    // version back from the backup taken before the upgrade, then freshly
+    // installed ones are removed, dependents first. Errors don't stop the
+    // rollback; the transaction stays pending until everything is undone, so
+    // 'raven abort' can be run again.
+    pub async fn abort_transaction(&self) -> Result<AbortReport, RavenError> {
+        let pending = self
+            .pending_transaction()
+            .await?
+            .ok_or_else(|| RavenError::TransactionError("No pending transaction".into()))?;
+
+        let mut report = AbortReport {
+            id: pending.id,
+            removed: Vec::new(),
+            restored: Vec::new(),
+            failed: Vec::new(),
+        };
+
+        // A failed step may have overwritten part of the old version too
+        for step in pending.steps.iter().rev() {
+            let Some(prev) = &step.previous_version else {
+                continue;
+            };
+            match self.restore_package(pending.id, &step.package).await {
+                Ok(true) => report.restored.push((step.package.clone(), prev.clone())),
+                Ok(false) if step.done => report.failed.push((
+                    step.package.clone(),
+                    format!(
+                        "no backup of v{} was kept; reinstall that version by hand",
+                        prev
+                    ),
+                )),
+                Ok(false) => {}
+                Err(e) => report.failed.push((step.package.clone(), e.to_string())),
+            }
+        }
+
+        // The plan lists dependencies first, so dependents go first in reverse.
+        // Anything still needed is retried once the rest is gone.
+        let mut remaining: Vec<PackageName> = pending
+            .steps
+            .iter()
+            .rev()
+            .filter(|s| s.done && s.previous_version.is_none())
+            .map(|s| s.package.clone())
+            .collect();
+        loop {
+            let freed = report.removed.len();
+            let mut blocked = Vec::new();
+            for pkg in remaining.drain(..) {
+                match self.remove_package(&pkg).await {
+                    Ok(()) => report.removed.push(pkg),
+                    Err(e @ RavenError::DependencyError(_)) => blocked.push((pkg, e)),
+                    Err(e) => report.failed.push((pkg, e.to_string())),
+                }
+            }
+            if blocked.is_empty() || report.removed.len() == freed {
+                let blocked = blocked.into_iter().map(|(pkg, e)| (pkg, e.to_string()));
+                report.failed.extend(blocked);
+                break;
+            }
+            remaining = blocked.into_iter().map(|(pkg, _)| pkg).collect();
+        }
+
+        if report.failed.is_empty() {
+            self.set_transaction_status(pending.id, "aborted").await?;
+            self.discard_backups(pending.id).await?;
+        } else {
+            self.set_transaction_status(pending.id, "failed").await?;
+        }
+        Ok(report)
+    }
+
+    fn backup_dir(&self, tx_id: i64, name: &PackageName) -> PathBuf {
+        self.staging_root
+            .join(format!("backup-{}", tx_id))
+            .join(&name.0)
+    }
+
+    // Keep the installed files and records of `name` before a transaction
+    // replaces them. The first backup wins, so resuming keeps the original.
+    pub async fn backup_package(&self, tx_id: i64, name: &PackageName) -> Result<(), RavenError> {
+        let dir = self.backup_dir(tx_id, name);
+        if dir.join("package.toml").exists() {
+            return Ok(());
+        }
+        let row: Option<(String, String, Option<String>, String)> = sqlx::query_as(
+            "SELECT version, hash, manifest_hash, options FROM packages WHERE name = ?",
+        )
+        .bind(&name.0)
+        .fetch_optional(&self.db)
+        .await?;
+        let Some((version, hash, manifest_hash, options)) = row else {
+            return Ok(());
+        };
+
+        let files: Vec<(String,)> =
+            sqlx::query_as("SELECT filepath FROM package_files WHERE package_name = ?")
+                .bind(&name.0)
+                .fetch_all(&self.db)
+                .await?;
+        let dependencies: Vec<(String,)> =
+            sqlx::query_as("SELECT depends_on FROM dependencies WHERE package = ?")
+                .bind(&name.0)
+                .fetch_all(&self.db)
+                .await?;
+        let sonames: Vec<(String, String)> =
+            sqlx::query_as("SELECT soname, kind FROM package_sonames WHERE package = ?")
+                .bind(&name.0)
+                .fetch_all(&self.db)
+                .await?;
+
+        let root = dir.join("root");
+        for (f,) in &files {
+            let path = Path::new(f);
+            if !path.exists() {
+                continue;
+            }
+            let copy = root.join(path.strip_prefix("/").unwrap_or(path));
+            if let Some(parent) = copy.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::copy(path, &copy).await?;
+        }
+
+        let backup = PackageBackup {
+            version,
+            hash,
+            manifest_hash,
+            options,
+            files: files.into_iter().map(|(f,)| f).collect(),
+            dependencies: dependencies.into_iter().map(|(d,)| d).collect(),
+            sonames,
+        };
+        let content = toml::to_string(&backup).map_err(|e| std::io::Error::other(e.to_string()))?;
+        // Written last: its presence marks a complete backup
+        tokio::fs::write(dir.join("package.toml"), content).await?;
+        Ok(())
+    }
+
+    // Put back what backup_package kept. Returns false when there is no backup.
+    async fn restore_package(&self, tx_id: i64, name: &PackageName) -> Result<bool, RavenError> {
+        let dir = self.backup_dir(tx_id, name);
+        let content = match tokio::fs::read_to_string(dir.join("package.toml")).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let backup: PackageBackup = toml::from_str(&content)?;
+
+        // Files only the new version brought along
+        let current: Vec<(String,)> =
+            sqlx::query_as("SELECT filepath FROM package_files WHERE package_name = ?")
+                .bind(&name.0)
+                .fetch_all(&self.db)
+                .await?;
+        for (f,) in current {
+            if !backup.files.contains(&f) {
+                let _ = tokio::fs::remove_file(&f).await;
+            }
+        }
+
+        let root = dir.join("root");
+        for f in &backup.files {
+            let path = Path::new(f);
+            let copy = root.join(path.strip_prefix("/").unwrap_or(path));
+            if !copy.exists() {
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            if path.exists() {
+                tokio::fs::remove_file(path).await?;
+            }
+            tokio::fs::copy(&copy, path).await?;
+        }
+
+        let mut tx = self.db.begin().await?;
+        for table in [
+            "package_files WHERE package_name",
+            "dependencies WHERE package",
+            "package_sonames WHERE package",
+        ] {
+            sqlx::query(&format!("DELETE FROM {} = ?", table))
+                .bind(&name.0)
+                .execute(&mut *tx)
+                .await?;
+        }
+        for f in &backup.files {
+            sqlx::query("INSERT INTO package_files (package_name, filepath) VALUES (?, ?)")
+                .bind(&name.0)
+                .bind(f)
+                .execute(&mut *tx)
+                .await?;
+        }
+        for dep in &backup.dependencies {
+            sqlx::query("INSERT INTO dependencies (package, depends_on) VALUES (?, ?)")
+                .bind(&name.0)
+                .bind(dep)
+                .execute(&mut *tx)
+                .await?;
+        }
+        for (soname, kind) in &backup.sonames {
+            sqlx::query("INSERT INTO package_sonames (package, soname, kind) VALUES (?, ?, ?)")
+                .bind(&name.0)
+                .bind(soname)
+                .bind(kind)
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query(
+            "INSERT OR REPLACE INTO packages (name, version, hash, manifest_hash, options) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&name.0)
+        .bind(&backup.version)
+        .bind(&backup.hash)
+        .bind(&backup.manifest_hash)
+        .bind(&backup.options)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // Backups are only needed while their transaction can still be aborted
+    pub async fn discard_backups(&self, tx_id: i64) -> Result<(), RavenError> {
+        match tokio::fs::remove_dir_all(self.staging_root.join(format!("backup-{}", tx_id))).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // Register one package built from `recipe` (the recipe's own or a sub-package)
     pub async fn install_package(
         &self,
         recipe: &Recipe,
+        name: &PackageName,
         artifact_path: &Path,
+        sonames: &SonameScan,
     ) -> Result<(), RavenError> {
+        let deps = recipe.package_dependencies(name)?;
         let mut tx = self.db.begin().await?;
 
         let pkg_stage = self
             .staging_root
-            .join(format!("{}_{}", recipe.name.0, recipe.version));
+            .join(format!("{}_{}", name.0, recipe.version));
         if pkg_stage.exists() {
             tokio::fs::remove_dir_all(&pkg_stage).await?;
         }
@@ -147,8 +1139,7 @@ impl TransactionManager {
             .status()?;
 
         if !status.success() {
-            return Err(RavenError::IoError(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(RavenError::IoError(std::io::Error::other(
                 "Failed to copy artifacts to staging",
             )));
         }
@@ -183,27 +1174,49 @@ impl TransactionManager {
                 sqlx::query(
                     "INSERT OR REPLACE INTO package_files (package_name, filepath) VALUES (?, ?)",
                 )
-                .bind(&recipe.name.0)
+                .bind(&name.0)
                 .bind(dest.to_string_lossy().to_string())
                 .execute(&mut *tx)
                 .await?;
             }
         }
 
-        for dep in &recipe.dependencies {
-            let parts: Vec<&str> = dep.split_whitespace().collect();
-            let dep_name = parts[0];
+        for dep in deps {
             sqlx::query("INSERT OR IGNORE INTO dependencies (package, depends_on) VALUES (?, ?)")
-                .bind(&recipe.name.0)
-                .bind(dep_name)
+                .bind(&name.0)
+                .bind(dep.name.0)
                 .execute(&mut *tx)
                 .await?;
         }
 
-        sqlx::query("INSERT OR REPLACE INTO packages (name, version, hash) VALUES (?, ?, ?)")
-            .bind(&recipe.name.0)
+        sqlx::query("DELETE FROM package_sonames WHERE package = ?")
+            .bind(&name.0)
+            .execute(&mut *tx)
+            .await?;
+        let rows = (sonames.provides.iter().map(|s| (s, "provides")))
+            .chain(sonames.needs.iter().map(|s| (s, "needs")));
+        for (soname, kind) in rows {
+            sqlx::query("INSERT INTO package_sonames (package, soname, kind) VALUES (?, ?, ?)")
+                .bind(&name.0)
+                .bind(soname)
+                .bind(kind)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        let root = artifact_path.to_path_buf();
+        let manifest = tokio::task::spawn_blocking(move || Manifest::scan(&root))
+            .await
+            .map_err(|e| RavenError::IoError(std::io::Error::other(e)))??;
+
+        sqlx::query(
+            "INSERT OR REPLACE INTO packages (name, version, hash, manifest_hash, options) VALUES (?, ?, ?, ?, ?)",
+        )
+            .bind(&name.0)
             .bind(&recipe.version)
-            .bind(&recipe.sha256_sum.0)
+            .bind(recipe.build_key())
+            .bind(manifest.digest())
+            .bind(recipe.option_string())
             .execute(&mut *tx)
             .await?;
 
@@ -252,6 +1265,10 @@ impl TransactionManager {
             .bind(&pkg_name.0)
             .execute(&mut *tx)
             .await?;
+        sqlx::query("DELETE FROM package_sonames WHERE package = ?")
+            .bind(&pkg_name.0)
+            .execute(&mut *tx)
+            .await?;
         sqlx::query("DELETE FROM dependencies WHERE package = ?")
             .bind(&pkg_name.0)
             .execute(&mut *tx)
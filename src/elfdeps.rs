@@ -0,0 +1,46 @@
+use crate::core::RavenError;
+use goblin::elf::Elf;
+use std::collections::BTreeSet;
+use std::path::Path;
+use walkdir::WalkDir;
+
+// Shared-library interface of an artifact tree: the sonames its libraries
+// provide (DT_SONAME) and the ones its binaries load (DT_NEEDED)
+#[derive(Debug, Clone, Default)]
+pub struct SonameScan {
+    pub provides: BTreeSet<String>,
+    pub needs: BTreeSet<String>,
+}
+
+impl SonameScan {
+    // Blocking
+    pub fn scan(root: &Path) -> Result<Self, RavenError> {
+        let mut scan = Self::default();
+
+        for entry in WalkDir::new(root).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let data = std::fs::read(entry.path())?;
+            if !data.starts_with(b"\x7fELF") {
+                continue;
+            }
+            // Not every ELF file is something we can parse (firmware, odd objects)
+            let Ok(elf) = Elf::parse(&data) else {
+                continue;
+            };
+            if let Some(soname) = elf.soname {
+                scan.provides.insert(soname.to_string());
+            }
+            scan.needs
+                .extend(elf.libraries.iter().map(|lib| lib.to_string()));
+        }
+
+        Ok(scan)
+    }
+
+    // Needed sonames this tree does not satisfy by itself
+    pub fn external_needs(&self) -> impl Iterator<Item = &String> {
+        self.needs.iter().filter(|n| !self.provides.contains(*n))
+    }
+}
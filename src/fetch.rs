@@ -0,0 +1,400 @@
+use crate::checksum::{hash_prefix, verify_file, Checksums, MultiHasher};
+use crate::config::NetworkConfig;
+use crate::core::RavenError;
+use crate::ui::{create_download_bar, create_spinner};
+use crate::vcs::GitSource;
+use futures::future::BoxFuture;
+use indicatif::MultiProgress;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+// Retrieves one URL into `dest`. Implementations must leave `dest` holding
+// content matching every digest in `expected` (git sources pass none; the
+// commit pin in the URL stands in for them).
+pub trait Fetcher: Send + Sync {
+    fn fetch<'a>(
+        &'a self,
+        url: &'a str,
+        dest: &'a Path,
+        expected: &'a Checksums,
+        pkg_name: &'a str,
+    ) -> BoxFuture<'a, Result<(), RavenError>>;
+
+    // Whether the fetcher may be called in offline mode. Local fetchers can;
+    // so can those that enforce offline mode themselves from a cache.
+    fn works_offline(&self, _url: &str) -> bool {
+        false
+    }
+}
+
+// Scheme -> Fetcher dispatch. Plain paths use the "path" scheme.
+pub struct FetcherRegistry {
+    fetchers: HashMap<String, Arc<dyn Fetcher>>,
+}
+
+impl FetcherRegistry {
+    pub fn new() -> Self {
+        Self {
+            fetchers: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, scheme: &str, fetcher: Arc<dyn Fetcher>) {
+        self.fetchers.insert(scheme.to_string(), fetcher);
+    }
+
+    pub fn for_url(&self, url: &str) -> Result<Arc<dyn Fetcher>, RavenError> {
+        let scheme = scheme_of(url);
+        self.fetchers.get(scheme).cloned().ok_or_else(|| {
+            RavenError::FetchError(format!("No fetcher registered for '{}' ({})", scheme, url))
+        })
+    }
+}
+
+// "git+https://..." -> "git", "https://..." -> "https", "/srv/x.tar" -> "path"
+pub fn scheme_of(url: &str) -> &str {
+    match url.split_once("://") {
+        Some((scheme, _)) => scheme.split('+').next().unwrap_or(scheme),
+        None => "path",
+    }
+}
+
+pub struct HttpFetcher {
+    network: NetworkConfig,
+    progress: MultiProgress,
+}
+
+impl HttpFetcher {
+    pub fn new(network: NetworkConfig, progress: MultiProgress) -> Self {
+        Self { network, progress }
+    }
+
+    // Robust download logic
+    async fn download_with_retry(
+        &self,
+        url: &str,
+        path: &Path,
+        expected: &Checksums,
+        pkg_name: &str,
+    ) -> Result<(), RavenError> {
+        let max_retries = self.network.retries.max(1);
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            match self.download_inner(url, path, expected, pkg_name).await {
+                Ok(_) => return Ok(()),
+                // 4xx or a bad file: the next mirror is the better bet
+                Err(e) if attempt >= max_retries || !is_transient(&e) => return Err(e),
+                Err(_) => tokio::time::sleep(self.network.backoff(attempt)).await,
+            }
+        }
+    }
+
+    // Downloads into `path` (a .part file), resuming from whatever is already
+    // there via an HTTP Range request. The existing prefix is re-hashed so the
+    // final digest always covers the whole file.
+    async fn download_inner(
+        &self,
+        url: &str,
+        path: &Path,
+        expected: &Checksums,
+        pkg_name: &str,
+    ) -> Result<(), RavenError> {
+        let net = &self.network;
+        let client = reqwest::Client::builder()
+            .user_agent("RavenPackageManager/1.0 (MissionCritical)")
+            .connect_timeout(Duration::from_secs(net.connect_timeout_secs))
+            .build()
+            .map_err(RavenError::NetworkError)?;
+
+        let mut existing = match tokio::fs::metadata(path).await {
+            Ok(m) => m.len(),
+            Err(_) => 0,
+        };
+
+        let mut request = client.get(url);
+        if existing > 0 {
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-", existing));
+        }
+        let mut resp = request.send().await?;
+
+        // Nothing left to fetch: the partial file may already be complete
+        if resp.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+            let result = verify_file(path, expected).await;
+            if result.is_err() {
+                tokio::fs::remove_file(path).await?;
+            }
+            return result;
+        }
+
+        if !resp.status().is_success() {
+            return Err(RavenError::NetworkError(
+                resp.error_for_status().unwrap_err(),
+            ));
+        }
+
+        // 200 instead of 206: the server ignored the range, start over
+        let resumed = existing > 0 && resp.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        if !resumed {
+            existing = 0;
+        }
+
+        let mut hasher = if resumed {
+            hash_prefix(path, expected).await?
+        } else {
+            MultiHasher::new(expected)
+        };
+
+        let mut file = if resumed {
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(path)
+                .await?
+        } else {
+            tokio::fs::File::create(path).await?
+        };
+
+        let total_size = existing + resp.content_length().unwrap_or(0);
+        let pb = self.progress.add(create_download_bar(total_size, pkg_name));
+        pb.set_position(existing);
+
+        let read_timeout = Duration::from_secs(net.read_timeout_secs);
+        let started = std::time::Instant::now();
+        let mut received: u64 = 0;
+
+        loop {
+            let chunk = tokio::time::timeout(read_timeout, resp.chunk())
+                .await
+                .map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("No data from {} for {}s", url, net.read_timeout_secs),
+                    )
+                })??;
+            let Some(chunk) = chunk else { break };
+
+            file.write_all(&chunk).await?;
+            hasher.update(&chunk);
+            received += chunk.len() as u64;
+            pb.set_position(existing + received);
+
+            // Throttle to the configured bandwidth
+            if let Some(limit) = net.bandwidth_limit_kib.filter(|l| *l > 0) {
+                let expected = Duration::from_secs_f64(received as f64 / (limit * 1024) as f64);
+                let elapsed = started.elapsed();
+                if expected > elapsed {
+                    tokio::time::sleep(expected - elapsed).await;
+                }
+            }
+        }
+        file.flush().await?;
+
+        pb.finish_with_message("Download complete");
+
+        let result = expected.verify(&hasher.finalize());
+        if result.is_err() {
+            // A corrupt prefix would poison every resume; start clean next time
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        result
+    }
+}
+
+impl Fetcher for HttpFetcher {
+    fn fetch<'a>(
+        &'a self,
+        url: &'a str,
+        dest: &'a Path,
+        expected: &'a Checksums,
+        pkg_name: &'a str,
+    ) -> BoxFuture<'a, Result<(), RavenError>> {
+        Box::pin(self.download_with_retry(url, dest, expected, pkg_name))
+    }
+}
+
+// file:///abs/path and plain local paths
+pub struct FileFetcher;
+
+impl Fetcher for FileFetcher {
+    fn fetch<'a>(
+        &'a self,
+        url: &'a str,
+        dest: &'a Path,
+        expected: &'a Checksums,
+        _pkg_name: &'a str,
+    ) -> BoxFuture<'a, Result<(), RavenError>> {
+        Box::pin(async move {
+            let src = Path::new(url.strip_prefix("file://").unwrap_or(url));
+            tokio::fs::copy(&src, dest).await.map_err(|e| {
+                RavenError::FetchError(format!("Cannot read {}: {}", src.display(), e))
+            })?;
+            verify_and_discard(dest, expected).await
+        })
+    }
+
+    fn works_offline(&self, _url: &str) -> bool {
+        true
+    }
+}
+
+// git+<url>#commit=<sha>: mirror into the git cache, then export the pinned tree
+pub struct GitFetcher {
+    cache_dir: PathBuf,
+    offline: bool,
+    progress: MultiProgress,
+}
+
+impl GitFetcher {
+    pub fn new(cache_dir: PathBuf, offline: bool, progress: MultiProgress) -> Self {
+        Self {
+            cache_dir,
+            offline,
+            progress,
+        }
+    }
+}
+
+impl Fetcher for GitFetcher {
+    fn fetch<'a>(
+        &'a self,
+        url: &'a str,
+        dest: &'a Path,
+        _expected: &'a Checksums,
+        _pkg_name: &'a str,
+    ) -> BoxFuture<'a, Result<(), RavenError>> {
+        Box::pin(async move {
+            let git = GitSource::parse(url)?;
+            let spinner = self.progress.add(create_spinner(&format!(
+                "Fetching {} @ {}...",
+                git.url,
+                &git.commit[..12]
+            )));
+
+            let cache = self.cache_dir.clone();
+            let offline = self.offline && !url.starts_with("git+file://");
+            let out = dest.to_path_buf();
+
+            let result = tokio::task::spawn_blocking(move || {
+                let repo = git.fetch(&cache, offline)?;
+                git.archive(&repo, &out)
+            })
+            .await
+            .map_err(|e| RavenError::IoError(std::io::Error::other(e)))?;
+
+            spinner.finish_and_clear();
+            result
+        })
+    }
+
+    // GitSource::fetch serves pinned commits from the git cache and refuses
+    // to touch the network offline
+    fn works_offline(&self, _url: &str) -> bool {
+        true
+    }
+}
+
+// Custom scheme served by a local command, e.g.
+// `artifact = "artifact-get {url} {dest}"` in the [fetchers] config table
+pub struct CommandFetcher {
+    command: String,
+}
+
+impl CommandFetcher {
+    pub fn new(command: String) -> Self {
+        Self { command }
+    }
+}
+
+impl Fetcher for CommandFetcher {
+    fn fetch<'a>(
+        &'a self,
+        url: &'a str,
+        dest: &'a Path,
+        expected: &'a Checksums,
+        _pkg_name: &'a str,
+    ) -> BoxFuture<'a, Result<(), RavenError>> {
+        Box::pin(async move {
+            let script = self
+                .command
+                .replace("{url}", &shell_quote(url))
+                .replace("{dest}", &shell_quote(&dest.to_string_lossy()));
+
+            let status = tokio::process::Command::new("/bin/sh")
+                .arg("-c")
+                .arg(&script)
+                .status()
+                .await?;
+            if !status.success() {
+                return Err(RavenError::FetchError(format!(
+                    "'{}' exited with {}",
+                    script, status
+                )));
+            }
+            verify_and_discard(dest, expected).await
+        })
+    }
+}
+
+// Worth another attempt against the same URL: timeouts, dropped connections
+// and server errors
+fn is_transient(err: &RavenError) -> bool {
+    match err {
+        RavenError::NetworkError(e) => match e.status() {
+            Some(status) => status.is_server_error(),
+            None => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+        },
+        RavenError::IoError(e) => matches!(
+            e.kind(),
+            std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+// Single-quoted for sh, safe whatever `s` contains
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+async fn verify_and_discard(path: &Path, expected: &Checksums) -> Result<(), RavenError> {
+    let result = verify_file(path, expected).await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scheme_of_strips_transport_suffix() {
+        assert_eq!(scheme_of("https://example.org/x.tar.gz"), "https");
+        assert_eq!(scheme_of("git+https://example.org/x.git#commit=abc"), "git");
+        assert_eq!(scheme_of("file:///srv/x.tar"), "file");
+        assert_eq!(scheme_of("/srv/x.tar"), "path");
+        assert_eq!(scheme_of("x.tar"), "path");
+    }
+
+    #[test]
+    fn only_transient_errors_are_retried() {
+        let io = |kind| RavenError::IoError(std::io::Error::from(kind));
+        assert!(is_transient(&io(std::io::ErrorKind::TimedOut)));
+        assert!(is_transient(&io(std::io::ErrorKind::ConnectionReset)));
+        assert!(!is_transient(&io(std::io::ErrorKind::PermissionDenied)));
+        assert!(!is_transient(&RavenError::HashMismatch {
+            algorithm: "sha256".into(),
+            expected: "00".into(),
+            actual: "11".into(),
+        }));
+    }
+}
@@ -0,0 +1,225 @@
+use crate::core::RavenError;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Raven's own GnuPG home holding the keys trusted for upstream signatures.
+// The user's keyring is never consulted.
+pub struct Keyring {
+    home: PathBuf,
+}
+
+pub struct KeyInfo {
+    pub fingerprint: String,
+    pub uid: String,
+}
+
+impl Keyring {
+    pub fn new(home: PathBuf) -> Self {
+        Self { home }
+    }
+
+    fn gpg(&self) -> Result<Command, RavenError> {
+        if !self.home.exists() {
+            std::fs::create_dir_all(&self.home)?;
+            std::fs::set_permissions(&self.home, std::fs::Permissions::from_mode(0o700))?;
+        }
+        let mut cmd = Command::new("gpg");
+        cmd.arg("--homedir").arg(&self.home).arg("--batch");
+        Ok(cmd)
+    }
+
+    fn run(&self, cmd: &mut Command) -> Result<String, RavenError> {
+        let output = cmd.output().map_err(|e| {
+            RavenError::SignatureError(format!("Cannot run gpg (is GnuPG installed?): {}", e))
+        })?;
+        if !output.status.success() {
+            return Err(RavenError::SignatureError(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    pub fn import(&self, key_file: &Path) -> Result<(), RavenError> {
+        self.run(self.gpg()?.arg("--import").arg(key_file))?;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Result<Vec<KeyInfo>, RavenError> {
+        let out = self.run(self.gpg()?.args(["--with-colons", "--list-keys"]))?;
+
+        // Colon format: a "pub" record is followed by its "fpr" and "uid" records
+        let mut keys: Vec<KeyInfo> = Vec::new();
+        let mut in_primary = false;
+        for line in out.lines() {
+            let fields: Vec<&str> = line.split(':').collect();
+            match fields.first() {
+                Some(&"pub") => in_primary = true,
+                Some(&"sub") => in_primary = false,
+                Some(&"fpr") if in_primary && fields.len() > 9 => {
+                    keys.push(KeyInfo {
+                        fingerprint: fields[9].to_string(),
+                        uid: String::new(),
+                    });
+                    in_primary = false;
+                }
+                Some(&"uid") if fields.len() > 9 => {
+                    if let Some(key) = keys.last_mut().filter(|k| k.uid.is_empty()) {
+                        key.uid = fields[9].to_string();
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(keys)
+    }
+
+    pub fn remove(&self, fingerprint: &str) -> Result<(), RavenError> {
+        let fpr = normalize_fingerprint(fingerprint);
+        self.run(self.gpg()?.args(["--yes", "--delete-keys", &fpr]))?;
+        Ok(())
+    }
+
+    // Check a detached signature and require it to come from one of `trusted`
+    // (matched against both the signing subkey and its primary key).
+    pub fn verify(
+        &self,
+        signature: &Path,
+        file: &Path,
+        trusted: &[String],
+    ) -> Result<String, RavenError> {
+        let output = self
+            .gpg()?
+            .args(["--status-fd", "1", "--verify"])
+            .arg(signature)
+            .arg(file)
+            .output()
+            .map_err(|e| {
+                RavenError::SignatureError(format!("Cannot run gpg (is GnuPG installed?): {}", e))
+            })?;
+
+        let status = String::from_utf8_lossy(&output.stdout);
+        let signer = match good_signer(&status) {
+            Ok(_) if !output.status.success() => Err("gpg failed".to_string()),
+            signer => signer,
+        };
+        let (subkey, primary) = signer.map_err(|reason| {
+            RavenError::SignatureError(format!(
+                "Bad or unverifiable signature for {} ({}): {}",
+                file.display(),
+                reason,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        })?;
+
+        let trusted: Vec<String> = trusted.iter().map(|f| normalize_fingerprint(f)).collect();
+        let signer = primary.unwrap_or_else(|| subkey.clone());
+        if trusted.contains(&signer) || trusted.contains(&subkey) {
+            Ok(signer)
+        } else {
+            Err(RavenError::SignatureError(format!(
+                "{} is signed by {}, which is not a trusted key for this recipe",
+                file.display(),
+                signer
+            )))
+        }
+    }
+}
+
+// Signing subkey and primary key fingerprints from gpg's `--status-fd`
+// output. gpg still reports VALIDSIG (and exits 0) for expired or revoked
+// keys, so a GOODSIG from the same key is required and those are refused.
+fn good_signer(status: &str) -> Result<(String, Option<String>), String> {
+    let mut good = None;
+    let mut valid = None;
+    for line in status.lines() {
+        let Some(rest) = line.strip_prefix("[GNUPG:] ") else {
+            continue;
+        };
+        let mut fields = rest.split_whitespace();
+        match fields.next() {
+            Some(keyword @ ("EXPKEYSIG" | "REVKEYSIG" | "EXPSIG" | "BADSIG" | "ERRSIG")) => {
+                return Err(match keyword {
+                    "EXPKEYSIG" => "signing key has expired",
+                    "REVKEYSIG" => "signing key has been revoked",
+                    "EXPSIG" => "signature has expired",
+                    "BADSIG" => "bad signature",
+                    _ => "signature could not be checked",
+                }
+                .to_string());
+            }
+            Some("GOODSIG") => good = fields.next().map(normalize_fingerprint),
+            Some("VALIDSIG") => {
+                let fields: Vec<&str> = fields.collect();
+                if let Some(subkey) = fields.first() {
+                    let primary = fields.get(9).map(|s| normalize_fingerprint(s));
+                    valid = Some((normalize_fingerprint(subkey), primary));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    match (good, valid) {
+        // GOODSIG names the key by its long key id, the fingerprint's tail
+        (Some(key_id), Some((subkey, primary))) if subkey.ends_with(&key_id) => {
+            Ok((subkey, primary))
+        }
+        (Some(_), Some(_)) => Err("GOODSIG and VALIDSIG name different keys".to_string()),
+        _ => Err("no good signature".to_string()),
+    }
+}
+
+pub fn normalize_fingerprint(fpr: &str) -> String {
+    fpr.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .trim_start_matches("0x")
+        .to_ascii_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FPR: &str = "0123456789ABCDEF0123456789ABCDEF01234567";
+
+    fn validsig() -> String {
+        format!(
+            "[GNUPG:] VALIDSIG {} 2024-01-01 1704067200 0 4 0 22 10 00 {}",
+            FPR, FPR
+        )
+    }
+
+    #[test]
+    fn accepts_good_and_valid_signature_from_same_key() {
+        let status = format!(
+            "[GNUPG:] NEWSIG\n[GNUPG:] GOODSIG 89ABCDEF01234567 Upstream <u@example.org>\n{}\n",
+            validsig()
+        );
+        assert_eq!(
+            good_signer(&status),
+            Ok((FPR.to_string(), Some(FPR.to_string())))
+        );
+    }
+
+    #[test]
+    fn rejects_expired_or_revoked_keys_despite_validsig() {
+        for keyword in ["EXPKEYSIG", "REVKEYSIG", "EXPSIG"] {
+            let status = format!(
+                "[GNUPG:] {} 89ABCDEF01234567 Upstream <u@example.org>\n{}\n",
+                keyword,
+                validsig()
+            );
+            assert!(good_signer(&status).is_err(), "{} accepted", keyword);
+        }
+    }
+
+    #[test]
+    fn requires_goodsig() {
+        assert!(good_signer(&validsig()).is_err());
+        let other = format!("[GNUPG:] GOODSIG FFFFFFFFFFFFFFFF someone\n{}", validsig());
+        assert!(good_signer(&other).is_err());
+    }
+}
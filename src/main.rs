@@ -1,15 +1,26 @@
+mod archive;
 mod builder;
+mod buildlog;
+mod checksum;
 mod config;
 mod core;
+mod elfdeps;
+mod fetch;
+mod keyring;
+mod manifest;
+mod postprocess;
 mod reactor;
 mod sandbox;
 mod search;
 mod sources;
+mod split;
 mod ui;
+mod vcs;
 
-use crate::builder::Builder;
+use crate::builder::{BuildOptions, Builder};
 use crate::config::ConfigManager;
-use crate::core::{PackageName, TransactionManager};
+use crate::core::{PackageName, Phase, RavenError, TransactionManager};
+use crate::keyring::Keyring;
 use crate::reactor::Reactor;
 use crate::search::SearchEngine;
 use crate::sources::SourceManager;
@@ -17,12 +28,21 @@ use crate::ui::{log_error, log_success, print_banner};
 use clap::{Parser, Subcommand};
 use owo_colors::OwoColorize;
 use semver::Version;
+use std::path::PathBuf;
 use std::process::exit;
 use std::sync::Arc;
 
 #[derive(Parser)]
 #[command(name = "raven")]
 struct Cli {
+    /// Never access the network; fail if a source is not in the cache
+    #[arg(long, global = true, action)]
+    offline: bool,
+
+    /// Skip the check phase (upstream test suites) of every build
+    #[arg(long, global = true, action)]
+    no_check: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -30,7 +50,60 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Install {
+        /// Packages, optionally with option choices: curl[+ssl,-docs]
         packages: Vec<String>,
+        /// Continue the last failed transaction instead of starting a new one
+        #[arg(long, action, conflicts_with = "packages")]
+        resume: bool,
+    },
+    Resume,
+    Abort,
+    /// Build a package without installing it (its dependencies must be installed)
+    Build {
+        /// Package, optionally with option choices: curl[+ssl,-docs]
+        package: String,
+        /// Reuse the previous build tree and start at this phase
+        #[arg(long)]
+        from_phase: Option<Phase>,
+        /// Stop after this phase
+        #[arg(long)]
+        until_phase: Option<Phase>,
+        /// Copy the package trees to this directory
+        #[arg(long, conflicts_with = "until_phase")]
+        output: Option<PathBuf>,
+        /// Build in a separate work dir that later builds do not delete
+        #[arg(long, conflicts_with = "from_phase")]
+        keep_build: bool,
+    },
+    /// Open a shell in a package's build sandbox
+    Shell {
+        package: String,
+        /// Run the build up to this phase first
+        #[arg(long, conflicts_with = "build_dir")]
+        until_phase: Option<Phase>,
+        /// Build tree to enter, e.g. one kept with --keep-build (default: the newest)
+        #[arg(long)]
+        build_dir: Option<PathBuf>,
+    },
+    /// Show the archived build logs of a package
+    Log {
+        package: String,
+        /// Print the most recent log
+        #[arg(long, conflicts_with = "id")]
+        last: bool,
+        /// Print the log with this id
+        #[arg(long)]
+        id: Option<i64>,
+        /// With --last: print the test-suite log instead of the build log
+        #[arg(long, requires = "last")]
+        check: bool,
+    },
+    /// Rebuild and reinstall a package
+    Rebuild {
+        package: String,
+        /// Only check that the build is reproducible; nothing is installed
+        #[arg(long)]
+        verify: bool,
     },
     Remove {
         packages: Vec<String>,
@@ -40,6 +113,18 @@ enum Commands {
     Search {
         query: String,
     },
+    /// Download sources into the distfiles cache without building
+    Fetch {
+        packages: Vec<String>,
+        /// Also fetch the sources of all dependencies
+        #[arg(long, action)]
+        deps: bool,
+    },
+    /// Manage the keys trusted for upstream source signatures
+    Key {
+        #[command(subcommand)]
+        action: KeyAction,
+    },
     Config {
         #[arg(long)]
         set_repo: Option<String>,
@@ -48,6 +133,13 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+enum KeyAction {
+    Import { file: std::path::PathBuf },
+    List,
+    Remove { fingerprint: String },
+}
+
 #[tokio::main]
 async fn main() {
     print_banner();
@@ -76,16 +168,189 @@ async fn run() -> anyhow::Result<()> {
         .await?,
     );
 
-    let builder = Arc::new(Builder::new("/tmp/raven_build".into()));
+    let mut build_config = config.clone();
+    build_config.offline |= cli.offline;
+    build_config.build.check &= !cli.no_check;
+
+    let builder = Arc::new(Builder::new("/tmp/raven_build".into(), build_config));
     let reactor = Reactor::new(tm.clone(), builder.clone());
 
-    let sm = SourceManager::new(raven_root.join("recipes"), config.repo_url.clone());
+    let mut sm = SourceManager::new(
+        raven_root.join("recipes"),
+        config.repo_url.clone(),
+        config.build.jobs(),
+        config.options.clone(),
+        config.post_process.split_debug,
+    );
 
     match cli.command {
-        Commands::Install { packages } => {
+        Commands::Install { packages, resume } => {
+            if resume {
+                restore_pending_options(&tm, &mut sm).await?;
+            } else {
+                restore_installed_options(&tm, &mut sm).await?;
+            }
+            let packages = parse_targets(packages, &mut sm)?;
+            let recipes = sm.load()?;
+            if resume {
+                reactor.resume(recipes).await?;
+            } else {
+                let targets = packages.into_iter().map(PackageName).collect();
+                reactor.execute(targets, recipes).await?;
+            }
+        }
+        Commands::Resume => {
+            restore_pending_options(&tm, &mut sm).await?;
             let recipes = sm.load()?;
-            let targets = packages.into_iter().map(PackageName).collect();
-            reactor.execute(targets, recipes).await?;
+            reactor.resume(recipes).await?;
+        }
+        Commands::Abort => {
+            let report = tm.abort_transaction().await?;
+            for pkg in &report.removed {
+                println!("   ➜ Removed {}", pkg.0.cyan());
+            }
+            for (pkg, prev) in &report.restored {
+                println!("   ➜ Restored {} v{}", pkg.0.cyan(), prev);
+            }
+            for (pkg, reason) in &report.failed {
+                eprintln!(
+                    "Warning: {} was not rolled back: {}",
+                    pkg.0.yellow(),
+                    reason
+                );
+            }
+            if !report.failed.is_empty() {
+                return Err(RavenError::TransactionError(format!(
+                    "Transaction #{} is only partly rolled back. Fix the problems above and run 'raven abort' again",
+                    report.id
+                ))
+                .into());
+            }
+            log_success(&format!("Transaction #{} aborted", report.id));
+        }
+        Commands::Build {
+            package,
+            from_phase,
+            until_phase,
+            output,
+            keep_build,
+        } => {
+            let package = parse_target(&package, &mut sm)?;
+            let recipes = sm.load()?;
+            let recipe = recipes.get(&PackageName(package.clone())).ok_or_else(|| {
+                RavenError::DependencyError(format!("Package not found: {}", package))
+            })?;
+            let opts = BuildOptions {
+                from_phase,
+                until_phase,
+                keep_build,
+            };
+            let artifact = reactor.build(recipe, &opts, None).await?;
+            match output {
+                Some(dest) => {
+                    let exported = artifact.export(&dest)?;
+                    log_success(&format!(
+                        "Artifacts of {} copied to {}",
+                        package,
+                        dest.display()
+                    ));
+                    for (pkg, dir) in artifact.packages.iter().zip(&exported) {
+                        println!("   ➜ {} {}", pkg.name.0.cyan(), dir.display());
+                    }
+                }
+                None => {
+                    log_success(&format!(
+                        "Artifacts of {} are in {}",
+                        package,
+                        artifact.root.display()
+                    ));
+                    for pkg in &artifact.packages {
+                        println!("   ➜ {} {}", pkg.name.0.cyan(), pkg.dir.display());
+                    }
+                }
+            }
+        }
+        Commands::Shell {
+            package,
+            until_phase,
+            build_dir,
+        } => {
+            let package = parse_target(&package, &mut sm)?;
+            let recipes = sm.load()?;
+            let recipe = recipes.get(&PackageName(package.clone())).ok_or_else(|| {
+                RavenError::DependencyError(format!("Package not found: {}", package))
+            })?;
+            if until_phase.is_some() {
+                let opts = BuildOptions {
+                    until_phase,
+                    ..Default::default()
+                };
+                reactor.build(recipe, &opts, None).await?;
+            }
+            builder.shell(recipe, build_dir.as_deref()).await?;
+        }
+        Commands::Log {
+            package,
+            last,
+            id,
+            check,
+        } => {
+            let logs = tm.build_logs(&PackageName(package.clone())).await?;
+            let selected = match id {
+                Some(id) => Some(logs.iter().find(|l| l.id == id).ok_or_else(|| {
+                    RavenError::DependencyError(format!("No build log #{} for {}", id, package))
+                })?),
+                None if last => {
+                    let kind = if check { "check" } else { "build" };
+                    Some(logs.iter().find(|l| l.kind == kind).ok_or_else(|| {
+                        RavenError::DependencyError(format!("No {} logs for {}", kind, package))
+                    })?)
+                }
+                None => None,
+            };
+
+            match selected {
+                Some(entry) => print!("{}", buildlog::read(&entry.path)?),
+                None => {
+                    println!(
+                        "{}",
+                        format!("BUILD LOGS OF {}", package).bold().underline()
+                    );
+                    if logs.is_empty() {
+                        println!("No build logs for '{}'", package);
+                    }
+                    for entry in &logs {
+                        let tx = entry
+                            .transaction_id
+                            .map(|t| format!(" (transaction #{})", t))
+                            .unwrap_or_default();
+                        println!(
+                            "#{:<5} {}  v{}  {:<5}  {}{}",
+                            entry.id,
+                            buildlog::format_utc(entry.created_at),
+                            entry.version,
+                            entry.kind,
+                            entry.status,
+                            tx
+                        );
+                    }
+                }
+            }
+        }
+        Commands::Rebuild { package, verify } => {
+            restore_installed_options(&tm, &mut sm).await?;
+            let package = parse_target(&package, &mut sm)?;
+            let recipes = sm.load()?;
+            if verify {
+                reactor
+                    .verify_rebuild(PackageName(package), recipes)
+                    .await?;
+            } else {
+                reactor
+                    .rebuild(PackageName(package.clone()), recipes)
+                    .await?;
+                log_success(&format!("Rebuilt {}", package));
+            }
         }
         Commands::Remove { packages } => {
             for p in packages {
@@ -101,7 +366,8 @@ async fn run() -> anyhow::Result<()> {
         Commands::Upgrade => {
             // 1. Get installed packages
             let installed = tm.list_installed().await?;
-            // 2. Load latest recipes
+            // 2. Load latest recipes, keeping the installed option choices
+            restore_installed_options(&tm, &mut sm).await?;
             let recipes = sm.load()?;
 
             let mut to_upgrade = Vec::new();
@@ -138,9 +404,55 @@ async fn run() -> anyhow::Result<()> {
         }
         Commands::Search { query } => {
             let recipes = sm.load()?;
-            let list: Vec<_> = recipes.values().cloned().collect();
+            // Sub-package names map to their recipe; list each recipe once
+            let list: Vec<_> = recipes
+                .iter()
+                .filter(|(name, recipe)| **name == recipe.name)
+                .map(|(_, recipe)| recipe.clone())
+                .collect();
             SearchEngine::search(&query, &list);
         }
+        Commands::Fetch { packages, deps } => {
+            restore_installed_options(&tm, &mut sm).await?;
+            let packages = parse_targets(packages, &mut sm)?;
+            let recipes = sm.load()?;
+            let targets: Vec<PackageName> = packages.into_iter().map(PackageName).collect();
+            let to_fetch = if deps {
+                reactor.resolve(targets, &recipes)?
+            } else {
+                targets
+            };
+
+            for pkg_name in &to_fetch {
+                let recipe = recipes.get(pkg_name).ok_or_else(|| {
+                    RavenError::DependencyError(format!("Package not found: {}", pkg_name.0))
+                })?;
+                builder.fetch_sources(recipe).await?;
+            }
+            log_success(&format!("Fetched sources for {} packages", to_fetch.len()));
+        }
+        Commands::Key { action } => {
+            let keyring = Keyring::new(config.keyring_dir.clone());
+            match action {
+                KeyAction::Import { file } => {
+                    keyring.import(&file)?;
+                    log_success(&format!("Imported keys from {}", file.display()));
+                }
+                KeyAction::List => {
+                    let keys = keyring.list()?;
+                    if keys.is_empty() {
+                        println!("No trusted keys. Use 'raven key import <file>'.");
+                    }
+                    for key in keys {
+                        println!("{}  {}", key.fingerprint.cyan(), key.uid);
+                    }
+                }
+                KeyAction::Remove { fingerprint } => {
+                    keyring.remove(&fingerprint)?;
+                    log_success(&format!("Removed key {}", fingerprint));
+                }
+            }
+        }
         Commands::Config { set_repo, show } => {
             if let Some(url) = set_repo {
                 config.repo_url = url.clone();
@@ -149,6 +461,9 @@ async fn run() -> anyhow::Result<()> {
             } else if show {
                 println!("Current Configuration:");
                 println!("   Repo URL: {}", config.repo_url);
+                for rule in &config.mirrors {
+                    println!("   Mirror:   {} -> {}", rule.prefix, rule.urls.join(", "));
+                }
             } else {
                 println!("Use --show or --set-repo <URL>");
             }
@@ -157,3 +472,100 @@ async fn run() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+// Re-planning keeps the options installed packages were built with
+async fn restore_installed_options(
+    tm: &TransactionManager,
+    sm: &mut SourceManager,
+) -> Result<(), RavenError> {
+    for (name, options) in tm.installed_options().await? {
+        sm.restore_options(&name.0, &options);
+    }
+    Ok(())
+}
+
+// Resuming builds each step with the options it was planned with
+async fn restore_pending_options(
+    tm: &TransactionManager,
+    sm: &mut SourceManager,
+) -> Result<(), RavenError> {
+    if let Some(pending) = tm.pending_transaction().await? {
+        for step in &pending.steps {
+            sm.restore_options(&step.package.0, &step.options);
+        }
+    }
+    Ok(())
+}
+
+// `curl[+ssl,-docs]` -> "curl", handing the choices to the source manager
+fn parse_target(arg: &str, sm: &mut SourceManager) -> Result<String, RavenError> {
+    let Some((package, rest)) = arg.split_once('[') else {
+        return Ok(arg.to_string());
+    };
+    let invalid = || {
+        RavenError::DependencyError(format!(
+            "Invalid option list in '{}'; expected e.g. curl[+ssl,-docs]",
+            arg
+        ))
+    };
+    let choices = rest.strip_suffix(']').ok_or_else(invalid)?;
+    for choice in choices.split(',').map(str::trim) {
+        let (on, option) = match choice.split_at_checked(1) {
+            Some(("+", option)) => (true, option),
+            Some(("-", option)) => (false, option),
+            _ => return Err(invalid()),
+        };
+        if option.is_empty() {
+            return Err(invalid());
+        }
+        sm.set_option(package, option, on);
+    }
+    Ok(package.to_string())
+}
+
+fn parse_targets(args: Vec<String>, sm: &mut SourceManager) -> Result<Vec<String>, RavenError> {
+    args.iter().map(|arg| parse_target(arg, sm)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OptionsConfig;
+
+    #[test]
+    fn parse_target_hands_choices_to_recipe() {
+        let dir = std::env::temp_dir().join(format!("raven-target-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("curl.toml"),
+            r#"
+name = "curl"
+version = "8.5.0"
+description = "curl"
+dependencies = []
+options = { ssl = false, docs = true }
+"#,
+        )
+        .unwrap();
+
+        let mut sm = SourceManager::new(
+            dir.clone(),
+            String::new(),
+            1,
+            OptionsConfig::default(),
+            false,
+        );
+        assert_eq!(parse_target("curl", &mut sm).unwrap(), "curl");
+        assert_eq!(parse_target("curl[+ssl, -docs]", &mut sm).unwrap(), "curl");
+        for bad in ["curl[ssl]", "curl[+ssl", "curl[+]", "curl[]"] {
+            assert!(parse_target(bad, &mut sm).is_err(), "{}", bad);
+        }
+
+        let recipes = sm.load().unwrap();
+        let options = &recipes[&PackageName("curl".into())].options;
+        assert!(options["ssl"]);
+        assert!(!options["docs"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+use crate::core::RavenError;
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::os::unix::fs::{lchown, MetadataExt};
+use std::path::Path;
+use walkdir::WalkDir;
+
+// One line per path of an artifact tree: type, mode, size and content digest
+// (or link target). Ownership and mtimes are normalized before scanning, so
+// two builds of the same recipe should produce identical manifests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    entries: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    // Blocking
+    pub fn scan(root: &Path) -> Result<Self, RavenError> {
+        let mut entries = BTreeMap::new();
+
+        for entry in WalkDir::new(root).min_depth(1) {
+            let entry = entry.map_err(|e| RavenError::IoError(std::io::Error::other(e)))?;
+            let path = entry.path();
+            let relative = path
+                .strip_prefix(root)
+                .unwrap()
+                .to_string_lossy()
+                .to_string();
+            let meta = entry.path().symlink_metadata()?;
+            let mode = meta.mode() & 0o7777;
+
+            let line = if meta.file_type().is_symlink() {
+                format!("l {:04o} {}", mode, std::fs::read_link(path)?.display())
+            } else if meta.is_dir() {
+                format!("d {:04o}", mode)
+            } else {
+                let mut hasher = Sha256::new();
+                std::io::copy(&mut File::open(path)?, &mut hasher)?;
+                format!(
+                    "f {:04o} {} {}",
+                    mode,
+                    meta.len(),
+                    hex::encode(hasher.finalize())
+                )
+            };
+            entries.insert(relative, line);
+        }
+
+        Ok(Self { entries })
+    }
+
+    pub fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        for (path, line) in &self.entries {
+            hasher.update(format!("{} {}\n", line, path).as_bytes());
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    // Human-readable differences, e.g. "~ usr/bin/foo: f 0755 ... -> f 0755 ..."
+    pub fn diff(&self, other: &Manifest) -> Vec<String> {
+        let mut out = Vec::new();
+        for (path, line) in &self.entries {
+            match other.entries.get(path) {
+                None => out.push(format!("- {}", path)),
+                Some(theirs) if theirs != line => {
+                    out.push(format!("~ {}: {} -> {}", path, line, theirs))
+                }
+                _ => {}
+            }
+        }
+        for path in other.entries.keys() {
+            if !self.entries.contains_key(path) {
+                out.push(format!("+ {}", path));
+            }
+        }
+        out
+    }
+}
+
+// Blocking: root ownership and a fixed mtime for everything under `root`
+pub fn normalize_tree(root: &Path, epoch: i64) -> Result<(), RavenError> {
+    let time = TimeSpec::new(epoch, 0);
+    for entry in WalkDir::new(root).contents_first(true) {
+        let entry = entry.map_err(|e| RavenError::IoError(std::io::Error::other(e)))?;
+        lchown(entry.path(), Some(0), Some(0))?;
+        set_time(entry.path(), &time)?;
+    }
+    Ok(())
+}
+
+// Blocking: mtime (and atime) of `path` itself, symlinks included
+pub fn set_mtime(path: &Path, epoch: i64) -> Result<(), RavenError> {
+    set_time(path, &TimeSpec::new(epoch, 0))
+}
+
+fn set_time(path: &Path, time: &TimeSpec) -> Result<(), RavenError> {
+    utimensat(None, path, time, time, UtimensatFlags::NoFollowSymlink)
+        .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
+    Ok(())
+}
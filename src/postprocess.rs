@@ -0,0 +1,262 @@
+use crate::config::{PostProcessConfig, TargetProfile};
+use crate::core::RavenError;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use walkdir::WalkDir;
+
+// Clean-up applied to the `out/` tree after the install phase
+pub struct PostProcessor {
+    opts: PostProcessConfig,
+    strip: String,
+    objcopy: String,
+}
+
+#[derive(Default)]
+pub struct PostProcessReport {
+    pub stripped: usize,
+    pub compressed: usize,
+    pub removed: usize,
+}
+
+enum ElfKind {
+    // Executables and shared objects
+    Linked,
+    // Object files and static archives
+    Relocatable,
+}
+
+impl PostProcessor {
+    pub fn new(opts: PostProcessConfig, target: Option<&TargetProfile>) -> Self {
+        let (strip, objcopy) = match target {
+            Some(t) => (t.tool(&t.strip, "strip"), t.tool(&None, "objcopy")),
+            None => ("strip".to_string(), "objcopy".to_string()),
+        };
+        Self {
+            opts,
+            strip,
+            objcopy,
+        }
+    }
+
+    // Blocking. Debug info split off binaries lands under `dbg/usr/lib/debug`.
+    pub fn run(&self, out: &Path, dbg: &Path) -> Result<PostProcessReport, RavenError> {
+        let mut report = PostProcessReport::default();
+
+        // 1. Drop files nobody should ship
+        let mut doomed: Vec<PathBuf> = Vec::new();
+        let purge = self.purge_set()?;
+        for entry in WalkDir::new(out)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(out).unwrap();
+            let is_la = self.opts.remove_la && relative.extension().is_some_and(|e| e == "la");
+            let is_info_dir = relative == Path::new("usr/share/info/dir");
+            if is_la || is_info_dir || purge.is_match(relative) {
+                doomed.push(entry.path().to_path_buf());
+            }
+        }
+        for path in &doomed {
+            std::fs::remove_file(path)?;
+            remove_empty_parents(path, out);
+        }
+        report.removed = doomed.len();
+
+        // 2. Strip binaries, optionally keeping their debug info
+        if self.opts.strip {
+            let files: Vec<PathBuf> = WalkDir::new(out)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .map(|e| e.into_path())
+                .collect();
+            for path in files {
+                if let Some(kind) = elf_kind(&path)? {
+                    let relative = path.strip_prefix(out).unwrap();
+                    self.strip_file(&path, relative, kind, dbg)?;
+                    report.stripped += 1;
+                }
+            }
+        }
+
+        // 3. Compress man and info pages
+        if self.opts.compress_man {
+            for dir in ["usr/share/man", "usr/share/info"] {
+                report.compressed += compress_pages(&out.join(dir))?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn purge_set(&self) -> Result<GlobSet, RavenError> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &self.opts.purge {
+            let glob = Glob::new(pattern.trim_start_matches('/')).map_err(|e| {
+                RavenError::BuildError(format!("Invalid purge pattern '{}': {}", pattern, e))
+            })?;
+            builder.add(glob);
+        }
+        builder
+            .build()
+            .map_err(|e| RavenError::BuildError(format!("Invalid purge patterns: {}", e)))
+    }
+
+    fn strip_file(
+        &self,
+        path: &Path,
+        relative: &Path,
+        kind: ElfKind,
+        dbg: &Path,
+    ) -> Result<(), RavenError> {
+        // Installed binaries are often read-only
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode | 0o200))?;
+
+        let result = match kind {
+            ElfKind::Relocatable => self.tool(&self.strip, &["--strip-debug"], path),
+            ElfKind::Linked if self.opts.split_debug => {
+                let debug_file = dbg
+                    .join("usr/lib/debug")
+                    .join(format!("{}.debug", relative.display()));
+                std::fs::create_dir_all(debug_file.parent().unwrap())?;
+                let link = format!("--add-gnu-debuglink={}", debug_file.display());
+
+                Command::new(&self.objcopy)
+                    .arg("--only-keep-debug")
+                    .arg(path)
+                    .arg(&debug_file)
+                    .status()
+                    .map_err(RavenError::IoError)
+                    .and_then(|s| check_tool(&self.objcopy, s, path))
+                    .and_then(|_| self.tool(&self.strip, &["--strip-unneeded"], path))
+                    .and_then(|_| self.tool(&self.objcopy, &[&link], path))
+            }
+            ElfKind::Linked => self.tool(&self.strip, &["--strip-unneeded"], path),
+        };
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        result
+    }
+
+    fn tool(&self, program: &str, args: &[&str], path: &Path) -> Result<(), RavenError> {
+        let status = Command::new(program)
+            .args(args)
+            .arg(path)
+            .status()
+            .map_err(|e| RavenError::BuildError(format!("Cannot run {}: {}", program, e)))?;
+        check_tool(program, status, path)
+    }
+}
+
+fn check_tool(
+    program: &str,
+    status: std::process::ExitStatus,
+    path: &Path,
+) -> Result<(), RavenError> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(RavenError::BuildError(format!(
+            "{} failed on {} ({})",
+            program,
+            path.display(),
+            status
+        )))
+    }
+}
+
+// ELF objects by header type; static archives count as relocatable
+fn elf_kind(path: &Path) -> Result<Option<ElfKind>, RavenError> {
+    let mut header = [0u8; 18];
+    let mut file = File::open(path)?;
+    let n = file.read(&mut header)?;
+
+    if n >= 8 && &header[..8] == b"!<arch>\n" {
+        return Ok(Some(ElfKind::Relocatable));
+    }
+    if n < 18 || &header[..4] != b"\x7fELF" {
+        return Ok(None);
+    }
+    let e_type = match header[5] {
+        2 => u16::from_be_bytes([header[16], header[17]]),
+        _ => u16::from_le_bytes([header[16], header[17]]),
+    };
+    Ok(match e_type {
+        1 => Some(ElfKind::Relocatable),
+        2 | 3 => Some(ElfKind::Linked),
+        _ => None,
+    })
+}
+
+// gzip every uncompressed page below `dir`, retargeting symlinks to match
+fn compress_pages(dir: &Path) -> Result<usize, RavenError> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let compressed_ext = ["gz", "bz2", "xz", "zst"];
+    let is_compressed = |p: &Path| {
+        p.extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| compressed_ext.contains(&e))
+    };
+
+    let entries: Vec<walkdir::DirEntry> = WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| !e.file_type().is_dir() && !is_compressed(e.path()))
+        .collect();
+
+    let mut count = 0;
+    for entry in entries.iter().filter(|e| e.file_type().is_file()) {
+        let path = entry.path();
+        let gz_path = with_gz(path);
+        let mut encoder = GzEncoder::new(File::create(&gz_path)?, Compression::best());
+        std::io::copy(&mut File::open(path)?, &mut encoder)?;
+        encoder.finish()?;
+        std::fs::set_permissions(&gz_path, std::fs::metadata(path)?.permissions())?;
+        std::fs::remove_file(path)?;
+        count += 1;
+    }
+
+    for entry in entries.iter().filter(|e| e.path_is_symlink()) {
+        let path = entry.path();
+        let target = std::fs::read_link(path)?;
+        let target = if is_compressed(&target) {
+            target
+        } else {
+            with_gz(&target)
+        };
+        std::fs::remove_file(path)?;
+        std::os::unix::fs::symlink(target, with_gz(path))?;
+    }
+
+    Ok(count)
+}
+
+fn with_gz(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+// Remove directories left empty below `root` after deleting `path`
+pub fn remove_empty_parents(path: &Path, root: &Path) {
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if d == root || std::fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
@@ -1,7 +1,10 @@
-use crate::builder::Builder;
+use crate::builder::{BuildArtifact, BuildOptions, Builder, PackageArtifact};
+use crate::config::SonamePolicy;
 use crate::core::TransactionManager;
 use crate::core::{PackageName, RavenError, Recipe};
+use crate::manifest::Manifest;
 use crate::ui::{create_spinner, log_success};
+use futures::stream::{self, StreamExt};
 use semver::Version;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
@@ -21,7 +24,32 @@ impl Reactor {
         targets: Vec<PackageName>,
         recipes: HashMap<PackageName, Recipe>,
     ) -> Result<(), RavenError> {
+        // Don't spend a download on a run that cannot start
+        self.tm.ensure_no_pending().await?;
+
         // 1. Resolve DAG (Directed Acyclic Graph)
+        let build_order = self.resolve(targets, &recipes)?;
+
+        // 2. Download everything up front so a dead URL fails the run before any build
+        self.prefetch(&build_order, &recipes).await?;
+
+        // 3. Persist the plan before touching the system
+        let plan: Vec<(PackageName, &Recipe)> = build_order
+            .iter()
+            .map(|name| (name.clone(), &recipes[name]))
+            .collect();
+        let tx_id = self.tm.begin_transaction(&plan).await?;
+
+        // 4. Execute Build & Install
+        self.run_transaction(tx_id, &recipes).await
+    }
+
+    // Topologically ordered build plan (dependencies first) for the given targets
+    pub fn resolve(
+        &self,
+        targets: Vec<PackageName>,
+        recipes: &HashMap<PackageName, Recipe>,
+    ) -> Result<Vec<PackageName>, RavenError> {
         let mut build_order = Vec::new();
         let mut visited = HashSet::new();
         let mut temp_visited = HashSet::new();
@@ -29,32 +57,324 @@ impl Reactor {
         for target in targets {
             self.visit(
                 &target,
-                &recipes,
+                recipes,
                 &mut visited,
                 &mut temp_visited,
                 &mut build_order,
             )?;
         }
 
-        // 2. Execute Build & Install
-        for pkg_name in build_order {
-            let recipe = recipes.get(&pkg_name).unwrap();
+        Ok(build_order)
+    }
+
+    // Continue a failed or interrupted transaction from its first unfinished step
+    pub async fn resume(&self, recipes: HashMap<PackageName, Recipe>) -> Result<(), RavenError> {
+        let pending = self.tm.pending_transaction().await?.ok_or_else(|| {
+            RavenError::TransactionError("No pending transaction to resume".into())
+        })?;
 
-            let spinner = create_spinner(&format!("Processing {}...", pkg_name.0));
+        let remaining: Vec<PackageName> = pending
+            .steps
+            .iter()
+            .filter(|s| !s.done)
+            .map(|s| s.package.clone())
+            .collect();
+        log_success(&format!(
+            "Resuming transaction #{} ({} of {} steps remaining)",
+            pending.id,
+            remaining.len(),
+            pending.steps.len()
+        ));
 
-            // Compile
-            let artifact = self.builder.build(recipe).await?;
+        self.prefetch(&remaining, &recipes).await?;
 
-            // ACID Install
-            self.tm.install_package(recipe, &artifact).await?;
+        self.run_transaction(pending.id, &recipes).await
+    }
+
+    // Rebuild and reinstall one package without touching its dependencies
+    pub async fn rebuild(
+        &self,
+        name: PackageName,
+        recipes: HashMap<PackageName, Recipe>,
+    ) -> Result<(), RavenError> {
+        let recipe = recipes
+            .get(&name)
+            .ok_or_else(|| RavenError::DependencyError(format!("Package not found: {}", name.0)))?;
+        let plan = [(name.clone(), recipe)];
+
+        self.tm.ensure_no_pending().await?;
+        self.prefetch(&[name], &recipes).await?;
+        let tx_id = self.tm.begin_transaction(&plan).await?;
+        self.run_transaction(tx_id, &recipes).await
+    }
+
+    // Check that a package builds bit-for-bit identically: against the manifest
+    // recorded at install time when it was built from the same sources,
+    // otherwise by building it twice.
+    pub async fn verify_rebuild(
+        &self,
+        name: PackageName,
+        recipes: HashMap<PackageName, Recipe>,
+    ) -> Result<(), RavenError> {
+        let recipe = recipes
+            .get(&name)
+            .ok_or_else(|| RavenError::DependencyError(format!("Package not found: {}", name.0)))?;
+        self.prefetch(std::slice::from_ref(&name), &recipes).await?;
+
+        let first = self.build_manifest(recipe, &name).await?;
+
+        if let Some((version, hash, Some(recorded))) = self.tm.installed_build(&name).await? {
+            if version == recipe.version && hash == recipe.build_key() {
+                if first.digest() == recorded {
+                    log_success(&format!(
+                        "{} is reproducible: manifest {} matches the installed build",
+                        name.0, recorded
+                    ));
+                    return Ok(());
+                }
+                return Err(RavenError::BuildError(format!(
+                    "{} is not reproducible: manifest {} differs from the installed {}",
+                    name.0,
+                    first.digest(),
+                    recorded
+                )));
+            }
+        }
+
+        let second = self.build_manifest(recipe, &name).await?;
+        let differences = first.diff(&second);
+        if differences.is_empty() {
+            log_success(&format!(
+                "{} is reproducible: both builds have manifest {}",
+                name.0,
+                first.digest()
+            ));
+            return Ok(());
+        }
+
+        for line in &differences {
+            println!("   {}", line);
+        }
+        Err(RavenError::BuildError(format!(
+            "{} is not reproducible: {} path(s) differ between two builds",
+            name.0,
+            differences.len()
+        )))
+    }
+
+    // Build through the builder and record the archived log, linked to the
+    // transaction that asked for the build (if any)
+    pub async fn build(
+        &self,
+        recipe: &Recipe,
+        opts: &BuildOptions,
+        tx_id: Option<i64>,
+    ) -> Result<BuildArtifact, RavenError> {
+        let result = self.builder.build(recipe, opts).await;
+        let (archived, failed, check_failed) = match &result {
+            Ok(artifact) => (artifact.logs.as_slice(), false, artifact.check_failed),
+            Err(RavenError::BuildFailed { archived, .. }) => (archived.as_slice(), true, false),
+            Err(_) => (&[][..], false, false),
+        };
+
+        for (kind, path) in archived {
+            let status = if failed || (*kind == "check" && check_failed) {
+                "failed"
+            } else {
+                "success"
+            };
+            self.tm
+                .record_build_log(&recipe.name, &recipe.version, kind, path, status, tx_id)
+                .await?;
+        }
+        if !archived.is_empty() {
+            let logs = &self.builder.config().logs;
+            self.tm
+                .prune_build_logs(logs.keep, logs.max_age_days)
+                .await?;
+        }
+        result
+    }
+
+    async fn build_manifest(
+        &self,
+        recipe: &Recipe,
+        name: &PackageName,
+    ) -> Result<Manifest, RavenError> {
+        let artifact = self.build(recipe, &BuildOptions::default(), None).await?;
+        let dir = artifact
+            .package(name)
+            .ok_or_else(|| not_produced(recipe, name))?
+            .dir
+            .clone();
+        tokio::task::spawn_blocking(move || Manifest::scan(&dir))
+            .await
+            .map_err(|e| RavenError::IoError(std::io::Error::other(e)))?
+    }
+
+    // Fetch and verify the sources of every package in the plan concurrently.
+    // All failures are reported together; nothing is built if any source fails.
+    pub async fn prefetch(
+        &self,
+        plan: &[PackageName],
+        recipes: &HashMap<PackageName, Recipe>,
+    ) -> Result<(), RavenError> {
+        let mut seen = HashSet::new();
+        let mut jobs = Vec::new();
+        for name in plan {
+            if let Some(recipe) = recipes.get(name) {
+                for source in recipe.all_sources() {
+                    // Identical files would race on the same cache entry
+                    if seen.insert(source.pin()) {
+                        jobs.push((name.clone(), source));
+                    }
+                }
+            }
+        }
+
+        let failures: Vec<String> = stream::iter(jobs)
+            .map(|(name, source)| async move {
+                self.builder
+                    .fetch_source(&recipes[&name], &source)
+                    .await
+                    .map_err(|e| format!("{} ({}): {}", name.0, source.url, e))
+            })
+            .buffer_unordered(self.builder.max_connections())
+            .filter_map(|r| async move { r.err() })
+            .collect()
+            .await;
+
+        if !failures.is_empty() {
+            return Err(RavenError::FetchError(format!(
+                "{} source(s), nothing was built:\n   {}",
+                failures.len(),
+                failures.join("\n   ")
+            )));
+        }
+        Ok(())
+    }
+
+    async fn run_transaction(
+        &self,
+        tx_id: i64,
+        recipes: &HashMap<PackageName, Recipe>,
+    ) -> Result<(), RavenError> {
+        let pending = self.tm.pending_transaction().await?.ok_or_else(|| {
+            RavenError::TransactionError(format!("Transaction #{} not found", tx_id))
+        })?;
+
+        self.tm.set_transaction_status(tx_id, "running").await?;
+
+        // Several packages of one recipe share a single build
+        let mut built: HashMap<PackageName, BuildArtifact> = HashMap::new();
+
+        for mut step in pending.steps.into_iter().filter(|s| !s.done) {
+            let recipe = match recipes.get(&step.package) {
+                Some(r) => r,
+                None => {
+                    self.tm.set_step_status(tx_id, &step, "failed").await?;
+                    self.tm.set_transaction_status(tx_id, "failed").await?;
+                    return Err(RavenError::DependencyError(format!(
+                        "Package not found: {}",
+                        step.package.0
+                    )));
+                }
+            };
+            // The recipe may have been fixed (and bumped) since the plan was made
+            step.version = recipe.version.clone();
+
+            let spinner = create_spinner(&format!("Processing {}...", step.package.0));
+
+            let result = async {
+                // Compile
+                if !built.contains_key(&recipe.name) {
+                    let artifact = self
+                        .build(recipe, &BuildOptions::default(), Some(tx_id))
+                        .await?;
+                    built.insert(recipe.name.clone(), artifact);
+                }
+                let package = built[&recipe.name]
+                    .package(&step.package)
+                    .ok_or_else(|| not_produced(recipe, &step.package))?;
+
+                // Catch undeclared shared-library dependencies before installing
+                if let Some(warning) = self.check_sonames(recipe, package).await? {
+                    spinner.suspend(|| eprintln!("Warning: {}", warning));
+                }
+
+                // ACID Install, keeping what an upgrade replaces for 'raven abort'
+                if step.previous_version.is_some() {
+                    self.tm.backup_package(tx_id, &package.name).await?;
+                }
+                self.tm
+                    .install_package(recipe, &package.name, &package.dir, &package.sonames)
+                    .await?;
+                Ok::<(), RavenError>(())
+            }
+            .await;
 
             spinner.finish_and_clear();
-            log_success(&format!("Installed {} v{}", pkg_name.0, recipe.version));
+
+            if let Err(e) = result {
+                self.tm.set_step_status(tx_id, &step, "failed").await?;
+                self.tm.set_transaction_status(tx_id, "failed").await?;
+                return Err(RavenError::TransactionError(format!(
+                    "{} failed: {}. Fix the recipe and run 'raven install --resume', or 'raven abort' to roll back",
+                    step.package.0, e
+                )));
+            }
+
+            self.tm.set_step_status(tx_id, &step, "done").await?;
+            log_success(&format!("Installed {} v{}", step.package.0, recipe.version));
         }
 
+        self.tm.set_transaction_status(tx_id, "completed").await?;
+        self.tm.discard_backups(tx_id).await?;
         Ok(())
     }
 
+    // Every DT_NEEDED must be provided by the package itself, its dependencies
+    // (transitively, as recorded at install time) or the ignore list.
+    // Returns the complaint when the policy only warns.
+    async fn check_sonames(
+        &self,
+        recipe: &Recipe,
+        package: &PackageArtifact,
+    ) -> Result<Option<String>, RavenError> {
+        let config = &self.builder.config().sonames;
+        if config.policy == SonamePolicy::Off {
+            return Ok(None);
+        }
+
+        let deps: Vec<PackageName> = recipe
+            .package_dependencies(&package.name)?
+            .into_iter()
+            .map(|d| d.name)
+            .collect();
+        let available = self.tm.provided_sonames(&deps).await?;
+        let ignore = config.ignore_set()?;
+
+        let missing: Vec<&str> = package
+            .sonames
+            .external_needs()
+            .filter(|n| !available.contains(*n) && !ignore.is_match(n.as_str()))
+            .map(String::as_str)
+            .collect();
+        if missing.is_empty() {
+            return Ok(None);
+        }
+
+        let message = format!(
+            "{} needs {} but none of its dependencies provide it; add the missing 'dependencies'",
+            package.name.0,
+            missing.join(", ")
+        );
+        match config.policy {
+            SonamePolicy::Error => Err(RavenError::DependencyError(message)),
+            _ => Ok(Some(message)),
+        }
+    }
+
     fn visit(
         &self,
         node: &PackageName,
@@ -81,9 +401,17 @@ impl Reactor {
             .ok_or_else(|| RavenError::DependencyError(format!("Package not found: {}", node.0)))?;
 
         // --- SEMVER VALIDATION ---
-        let deps = recipe.parse_dependencies()?;
+        // A sub-package needs its own runtime dependencies plus whatever
+        // the recipe needs to build
+        let mut deps = recipe.parse_dependencies()?;
+        if *node != recipe.name {
+            deps.extend(recipe.package_dependencies(node)?);
+        }
+        if self.builder.config().build.check && !recipe.check_commands.is_empty() {
+            deps.extend(recipe.parse_check_dependencies()?);
+        }
 
-        for dep_req in deps {
+        for dep_req in deps.into_iter().filter(|d| d.name != *node) {
             let candidate = recipes.get(&dep_req.name).ok_or_else(|| {
                 RavenError::DependencyError(format!("Missing dependency: {}", dep_req.name.0))
             })?;
@@ -114,3 +442,10 @@ impl Reactor {
         Ok(())
     }
 }
+
+fn not_produced(recipe: &Recipe, name: &PackageName) -> RavenError {
+    RavenError::BuildError(format!(
+        "{} produced no package {} (debug packages need post_process.split_debug)",
+        recipe.name.0, name.0
+    ))
+}
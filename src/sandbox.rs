@@ -1,101 +1,141 @@
 use crate::core::RavenError;
+use crate::fetch::shell_quote;
 use nix::mount::{mount, MsFlags};
 use nix::sched::{unshare, CloneFlags};
+use std::collections::BTreeMap;
 use std::os::unix::process::CommandExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 pub struct ScriptSandbox {
-    root: std::path::PathBuf,
+    root: PathBuf,
+    // Additional host directories bound at the same path (e.g. a sysroot)
+    extra_binds: Vec<PathBuf>,
+    // The complete environment of the script; nothing leaks in from the host
+    env: BTreeMap<String, String>,
 }
 
 impl ScriptSandbox {
     pub fn new(root: &Path) -> Self {
         Self {
             root: root.to_path_buf(),
+            extra_binds: Vec::new(),
+            env: BTreeMap::new(),
         }
     }
 
+    pub fn set_env(&mut self, env: BTreeMap<String, String>) {
+        self.env = env;
+    }
+
+    pub fn add_bind(&mut self, dir: &Path) {
+        self.extra_binds.push(dir.to_path_buf());
+    }
+
     pub fn run(&self, script: &str, log: std::fs::File) -> Result<(), RavenError> {
+        let output = self
+            .command(script)
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .spawn()
+            .map_err(RavenError::IoError)?
+            .wait_with_output()
+            .map_err(RavenError::IoError)?;
+
+        if !output.status.success() {
+            return Err(RavenError::BuildError(format!(
+                "script failed ({})",
+                output.status
+            )));
+        }
+        Ok(())
+    }
+
+    // Interactive shell on the terminal, started in `dir` (a sandbox path).
+    // The shell's exit status is the user's business, so it is not checked.
+    pub fn interactive(&self, dir: &str) -> Result<(), RavenError> {
+        let script = format!(
+            "cd {}\nif command -v bash >/dev/null; then exec bash --norc -i; fi\nexec sh -i\n",
+            shell_quote(dir)
+        );
+        self.command(&script)
+            .status()
+            .map_err(RavenError::IoError)?;
+        Ok(())
+    }
+
+    // `/bin/sh -c script` inside the jail, with only our environment
+    fn command(&self, script: &str) -> Command {
         let root = self.root.clone();
+        let extra_binds = self.extra_binds.clone();
 
-        let output = unsafe {
-            Command::new("/bin/sh")
-                .arg("-c")
-                .arg(script)
-                .stdout(log.try_clone().unwrap())
-                .stderr(log)
-                .pre_exec(move || {
-                    // 1. Isolate Filesystem & Hostname
-                    // NOTE: CLONE_NEWPID removed to avoid "cannot fork" errors in chroot without init
-                    unshare(CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUTS)
-                        .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(script).env_clear().envs(&self.env);
+        unsafe {
+            command.pre_exec(move || {
+                // 1. Isolate Filesystem & Hostname
+                // NOTE: CLONE_NEWPID removed to avoid "cannot fork" errors in chroot without init
+                unshare(CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUTS)
+                    .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
+
+                // 2. Make mount propagation private
+                mount(
+                    None::<&str>,
+                    "/",
+                    None::<&str>,
+                    MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+                    None::<&str>,
+                )
+                .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
 
-                    // 2. Make mount propagation private
+                // 3. Mount /proc (Crucial for build tools)
+                let proc_path = root.join("proc");
+                if proc_path.exists() {
                     mount(
-                        None::<&str>,
-                        "/",
-                        None::<&str>,
-                        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+                        Some("proc"),
+                        proc_path.as_path(),
+                        Some("proc"),
+                        MsFlags::empty(),
                         None::<&str>,
                     )
                     .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
+                }
+
+                // 4. Bind Mounts: Project host tools into sandbox
+                let bind_dirs = ["/bin", "/usr", "/lib", "/lib64", "/dev", "/etc"];
+
+                for dir in bind_dirs
+                    .iter()
+                    .map(Path::new)
+                    .chain(extra_binds.iter().map(PathBuf::as_path))
+                {
+                    let host_source = dir;
+                    let sandbox_target = root.join(dir.strip_prefix("/").unwrap_or(dir));
+
+                    if host_source.exists() {
+                        if !sandbox_target.exists() {
+                            let _ = std::fs::create_dir_all(&sandbox_target);
+                        }
 
-                    // 3. Mount /proc (Crucial for build tools)
-                    let proc_path = root.join("proc");
-                    if proc_path.exists() {
                         mount(
-                            Some("proc"),
-                            proc_path.as_path(),
-                            Some("proc"),
-                            MsFlags::empty(),
+                            Some(host_source),
+                            &sandbox_target,
+                            Some("none"),
+                            MsFlags::MS_BIND | MsFlags::MS_REC,
                             None::<&str>,
                         )
                         .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
                     }
+                }
 
-                    // 4. Bind Mounts: Project host tools into sandbox
-                    let bind_dirs = ["/bin", "/usr", "/lib", "/lib64", "/dev", "/etc"];
-
-                    for dir in bind_dirs {
-                        let host_source = Path::new(dir);
-                        let sandbox_target = root.join(dir.trim_start_matches('/'));
-
-                        if host_source.exists() {
-                            if !sandbox_target.exists() {
-                                let _ = std::fs::create_dir_all(&sandbox_target);
-                            }
-
-                            mount(
-                                Some(host_source),
-                                &sandbox_target,
-                                Some("none"),
-                                MsFlags::MS_BIND | MsFlags::MS_REC,
-                                None::<&str>,
-                            )
-                            .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
-                        }
-                    }
-
-                    // 5. Enter Jail
-                    nix::unistd::chroot(&root)
-                        .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
-                    nix::unistd::chdir("/")
-                        .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
-
-                    Ok(())
-                })
-                .spawn()
-        }
-        .map_err(|e| RavenError::IoError(e))?
-        .wait_with_output()
-        .map_err(|e| RavenError::IoError(e))?;
+                // 5. Enter Jail
+                nix::unistd::chroot(&root)
+                    .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
+                nix::unistd::chdir("/").map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
 
-        if !output.status.success() {
-            return Err(RavenError::DependencyError(
-                "Build script failed (check build.log)".into(),
-            ));
+                Ok(())
+            });
         }
-        Ok(())
+        command
     }
 }
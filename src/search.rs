@@ -18,7 +18,7 @@ impl SearchEngine {
         }
 
         // Sort by relevance (descending)
-        results.sort_by(|a, b| b.0.cmp(&a.0));
+        results.sort_by_key(|r| std::cmp::Reverse(r.0));
 
         println!("{}", "SEARCH RESULTS".bold().underline());
         if results.is_empty() {
@@ -1,23 +1,61 @@
-use crate::core::{PackageName, RavenError, Recipe};
+use crate::config::OptionsConfig;
+use crate::core::{parse_option_string, PackageName, RavenError, Recipe};
+use crate::vcs::GitSource;
 use git2::Repository;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
 use walkdir::WalkDir;
 
 pub struct SourceManager {
     local_path: PathBuf,
     remote_url: String,
+    // Value of `${jobs}` in recipes
+    jobs: usize,
+    // Choices applied over the recipes' option defaults
+    options: OptionsConfig,
+    // Option sets packages were installed or planned with
+    recorded: HashMap<String, BTreeMap<String, bool>>,
+    // Command-line choices
+    chosen: HashMap<String, BTreeMap<String, bool>>,
+    // Configured post_process.split_debug, unless a recipe overrides it
+    split_debug: bool,
 }
 
 impl SourceManager {
-    pub fn new(local_path: PathBuf, remote_url: String) -> Self {
+    pub fn new(
+        local_path: PathBuf,
+        remote_url: String,
+        jobs: usize,
+        options: OptionsConfig,
+        split_debug: bool,
+    ) -> Self {
         Self {
             local_path,
             remote_url,
+            jobs,
+            options,
+            recorded: HashMap::new(),
+            chosen: HashMap::new(),
+            split_debug,
         }
     }
 
+    // Command-line choice for one package, on top of everything else
+    pub fn set_option(&mut self, package: &str, option: &str, on: bool) {
+        self.chosen
+            .entry(package.to_string())
+            .or_default()
+            .insert(option.to_string(), on);
+    }
+
+    // Option set a package was installed or planned with (Recipe::option_string).
+    // It wins over the config so that re-planning keeps earlier choices.
+    pub fn restore_options(&mut self, package: &str, options: &str) {
+        self.recorded
+            .insert(package.to_string(), parse_option_string(options));
+    }
+
     pub fn sync(&self) -> Result<(), RavenError> {
         if !self.local_path.exists() {
             Repository::clone(&self.remote_url, &self.local_path)?;
@@ -26,7 +64,7 @@ impl SourceManager {
                 .current_dir(&self.local_path)
                 .arg("pull")
                 .status()
-                .map_err(|e| RavenError::IoError(e))?;
+                .map_err(RavenError::IoError)?;
 
             if !status.success() {
                 eprintln!("Warning: Failed to update recipes (offline mode?)");
@@ -37,6 +75,7 @@ impl SourceManager {
 
     pub fn load(&self) -> Result<HashMap<PackageName, Recipe>, RavenError> {
         let mut recipes = HashMap::new();
+        let mut loaded = Vec::new();
 
         for entry in WalkDir::new(&self.local_path)
             .min_depth(1)
@@ -46,7 +85,8 @@ impl SourceManager {
             if entry.path().extension().and_then(|s| s.to_str()) == Some("toml") {
                 let content = std::fs::read_to_string(entry.path())?;
 
-                let recipe: Recipe = toml::from_str(&content).map_err(RavenError::ParseError)?;
+                let mut recipe: Recipe =
+                    toml::from_str(&content).map_err(RavenError::ParseError)?;
 
                 if let Err(e) = semver::Version::parse(&recipe.version) {
                     return Err(RavenError::DependencyError(format!(
@@ -55,10 +95,416 @@ impl SourceManager {
                     )));
                 }
 
-                recipes.insert(recipe.name.clone(), recipe);
+                // Whether a `-dbg` package exists depends on it
+                let split_debug = recipe.post_process.split_debug.unwrap_or(self.split_debug);
+                recipe.post_process.split_debug = Some(split_debug);
+                self.apply_options(&mut recipe)?;
+                Self::interpolate(&mut recipe, self.jobs)?;
+                Self::validate_sources(&recipe)?;
+                Self::validate_packages(&recipe)?;
+                recipe.recipe_dir = entry
+                    .path()
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default();
+
+                loaded.push(recipe);
+            }
+        }
+
+        // Recipes first, then the packages they split off, which resolve to
+        // their recipe. An automatic `-dbg` name never shadows a real recipe.
+        for recipe in &loaded {
+            recipes.insert(recipe.name.clone(), recipe.clone());
+        }
+        for recipe in &loaded {
+            for name in recipe.package_names().into_iter().skip(1) {
+                let declared = recipe.packages.iter().any(|p| p.name == name);
+                match recipes.get(&name) {
+                    Some(other) if declared => {
+                        return Err(RavenError::InvalidRecipe(format!(
+                            "package {} is defined by both {} and {}",
+                            name.0, recipe.name.0, other.name.0
+                        )));
+                    }
+                    Some(_) => {}
+                    None => {
+                        recipes.insert(name, recipe.clone());
+                    }
+                }
             }
         }
 
         Ok(recipes)
     }
+
+    // Settle the effective option set, then drop the conditional list
+    // entries it rules out
+    fn apply_options(&self, recipe: &mut Recipe) -> Result<(), RavenError> {
+        let invalid =
+            |msg: String| RavenError::InvalidRecipe(format!("{}: {}", recipe.name.0, msg));
+
+        for (name, on) in &self.options.global {
+            if let Some(value) = recipe.options.get_mut(name) {
+                *value = *on;
+            }
+        }
+        // Then the configured, recorded and command-line choices, in that
+        // order. Choices for a sub-package apply to the recipe that builds it.
+        let names = recipe.package_names();
+        let choices = |map: &HashMap<String, BTreeMap<String, bool>>| {
+            names
+                .iter()
+                .filter_map(|n| map.get(&n.0))
+                .flatten()
+                .map(|(name, on)| (name.clone(), *on))
+                .collect::<Vec<_>>()
+        };
+        for (name, on) in choices(&self.options.packages) {
+            match recipe.options.get_mut(&name) {
+                Some(value) => *value = on,
+                None => return Err(invalid(format!("no option '{}'", name))),
+            }
+        }
+        // The recipe may have dropped an option since
+        for (name, on) in choices(&self.recorded) {
+            if let Some(value) = recipe.options.get_mut(&name) {
+                *value = on;
+            }
+        }
+        for (name, on) in choices(&self.chosen) {
+            match recipe.options.get_mut(&name) {
+                Some(value) => *value = on,
+                None => return Err(invalid(format!("no option '{}'", name))),
+            }
+        }
+
+        let options = recipe.options.clone();
+        let select = |field: &str, list: &mut Vec<String>| -> Result<(), RavenError> {
+            let mut kept = Vec::new();
+            for entry in list.drain(..) {
+                match conditional(&entry) {
+                    Some((name, wanted, rest)) => match options.get(name) {
+                        Some(on) if *on == wanted => kept.push(rest.to_string()),
+                        Some(_) => {}
+                        None => {
+                            return Err(invalid(format!("{}: unknown option '{}'", field, name)))
+                        }
+                    },
+                    None => kept.push(entry),
+                }
+            }
+            *list = kept;
+            Ok(())
+        };
+
+        select("dependencies", &mut recipe.dependencies)?;
+        select("check_dependencies", &mut recipe.check_dependencies)?;
+        select("prepare_commands", &mut recipe.prepare_commands)?;
+        select("configure_commands", &mut recipe.configure_commands)?;
+        select("build_commands", &mut recipe.build_commands)?;
+        select("check_commands", &mut recipe.check_commands)?;
+        select("install_commands", &mut recipe.install_commands)?;
+        for sub in &mut recipe.packages {
+            select("packages.dependencies", &mut sub.dependencies)?;
+        }
+        Ok(())
+    }
+
+    // Expand `${var}` in every URL, path and command list of the recipe
+    fn interpolate(recipe: &mut Recipe, jobs: usize) -> Result<(), RavenError> {
+        let invalid =
+            |msg: String| RavenError::InvalidRecipe(format!("{}: {}", recipe.name.0, msg));
+
+        let version = semver::Version::parse(&recipe.version)
+            .map_err(|e| invalid(format!("invalid version: {}", e)))?;
+
+        let mut vars: HashMap<String, String> = HashMap::from([
+            ("name".into(), recipe.name.0.clone()),
+            ("version".into(), recipe.version.clone()),
+            ("version_major".into(), version.major.to_string()),
+            ("version_minor".into(), version.minor.to_string()),
+            ("version_patch".into(), version.patch.to_string()),
+            (
+                "arch".into(),
+                recipe
+                    .target_arch
+                    .clone()
+                    .unwrap_or_else(|| std::env::consts::ARCH.to_string()),
+            ),
+            ("jobs".into(), jobs.to_string()),
+            ("prefix".into(), "/usr".into()),
+        ]);
+
+        // Custom vars may use the built-in ones, but not each other
+        let mut custom = HashMap::new();
+        for (key, value) in &recipe.vars {
+            if vars.contains_key(key) {
+                return Err(invalid(format!(
+                    "[vars] cannot redefine built-in '{}'",
+                    key
+                )));
+            }
+            // Values often end up in commands
+            let value = expand(value, &vars, true)
+                .map_err(|e| invalid(format!("[vars] {}: {}", key, e)))?;
+            custom.insert(key.clone(), value);
+        }
+        vars.extend(custom);
+
+        let expand_in = |field: &str, values: &mut [String], shell: bool| {
+            for value in values.iter_mut() {
+                *value = expand(value, &vars, shell)
+                    .map_err(|e| invalid(format!("{}: {}", field, e)))?;
+            }
+            Ok::<(), RavenError>(())
+        };
+        let expand_all = |field: &str, values: &mut [String]| expand_in(field, values, false);
+        let expand_shell = |field: &str, values: &mut [String]| expand_in(field, values, true);
+        let expand_opt = |field: &str, value: &mut Option<String>| -> Result<(), RavenError> {
+            match value {
+                Some(v) => expand_all(field, std::slice::from_mut(v)),
+                None => Ok(()),
+            }
+        };
+
+        expand_opt("source_url", &mut recipe.source_url)?;
+        expand_all("mirrors", &mut recipe.mirrors)?;
+        expand_opt("signature_url", &mut recipe.signature_url)?;
+        expand_opt("source_dir", &mut recipe.source_dir)?;
+        for source in &mut recipe.sources {
+            expand_all("sources.url", std::slice::from_mut(&mut source.url))?;
+            expand_all("sources.mirrors", &mut source.mirrors)?;
+            expand_opt("sources.signature_url", &mut source.signature_url)?;
+            expand_opt("sources.dest", &mut source.dest)?;
+        }
+        for patch in &mut recipe.patches {
+            expand_all("patches.file", std::slice::from_mut(&mut patch.file))?;
+        }
+        expand_shell("prepare_commands", &mut recipe.prepare_commands)?;
+        expand_shell("configure_commands", &mut recipe.configure_commands)?;
+        expand_shell("build_commands", &mut recipe.build_commands)?;
+        expand_shell("check_commands", &mut recipe.check_commands)?;
+        expand_all("dependencies", &mut recipe.dependencies)?;
+        expand_all("check_dependencies", &mut recipe.check_dependencies)?;
+        expand_shell("install_commands", &mut recipe.install_commands)?;
+        for sub in &mut recipe.packages {
+            expand_all("packages.dependencies", &mut sub.dependencies)?;
+            expand_all("packages.files", &mut sub.files)?;
+        }
+        for value in recipe
+            .env
+            .append
+            .values_mut()
+            .chain(recipe.env.set.values_mut())
+        {
+            expand_all("env", std::slice::from_mut(value))?;
+        }
+        Ok(())
+    }
+
+    fn validate_packages(recipe: &Recipe) -> Result<(), RavenError> {
+        let invalid =
+            |msg: String| RavenError::InvalidRecipe(format!("{}: {}", recipe.name.0, msg));
+
+        let mut seen = vec![recipe.name.clone()];
+        for sub in &recipe.packages {
+            if seen.contains(&sub.name) {
+                return Err(invalid(format!("package {} is declared twice", sub.name.0)));
+            }
+            seen.push(sub.name.clone());
+
+            if sub.files.is_empty() {
+                return Err(invalid(format!("package {} lists no files", sub.name.0)));
+            }
+            for pattern in &sub.files {
+                globset::Glob::new(pattern)
+                    .map_err(|e| invalid(format!("package {}: {}", sub.name.0, e)))?;
+            }
+            recipe.package_dependencies(&sub.name)?;
+        }
+        Ok(())
+    }
+
+    // Catch malformed source/patch declarations before any build starts
+    fn validate_sources(recipe: &Recipe) -> Result<(), RavenError> {
+        let invalid =
+            |msg: String| RavenError::InvalidRecipe(format!("{}: {}", recipe.name.0, msg));
+
+        if (recipe.sha256_sum.is_some()
+            || !recipe.checksums.is_empty()
+            || recipe.signature_url.is_some())
+            && recipe.source_url.is_none()
+        {
+            return Err(invalid(
+                "'sha256_sum'/'checksums'/'signature_url' given without 'source_url'".into(),
+            ));
+        }
+
+        // Whether signatures are required (and by whom) is decided by the
+        // config at build time, see SignatureConfig
+        for source in recipe.all_sources() {
+            if GitSource::is_git(&source.url) {
+                GitSource::parse(&source.url).map_err(|e| invalid(e.to_string()))?;
+                if source.signature_url.is_some() {
+                    return Err(invalid("git sources cannot carry a signature_url".into()));
+                }
+            } else {
+                let checksums = source.checksums();
+                if checksums.is_empty() {
+                    return Err(invalid(format!("source '{}' has no checksum", source.url)));
+                }
+                checksums
+                    .validate()
+                    .map_err(|e| invalid(format!("source '{}': {}", source.url, e)))?;
+            }
+            if let Some(dest) = &source.dest {
+                if !is_contained(dest) {
+                    return Err(invalid(format!(
+                        "source dest '{}' must stay inside /src",
+                        dest
+                    )));
+                }
+            }
+        }
+
+        if let Some(dir) = &recipe.source_dir {
+            if !is_contained(dir) {
+                return Err(invalid(format!(
+                    "source_dir '{}' must stay inside /src",
+                    dir
+                )));
+            }
+        }
+
+        for patch in &recipe.patches {
+            patch
+                .checksums()
+                .validate()
+                .map_err(|e| invalid(format!("patch '{}': {}", patch.file, e)))?;
+            if !is_contained(&patch.file) {
+                return Err(invalid(format!(
+                    "patch '{}' must be relative to the recipe directory",
+                    patch.file
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Replaces `${var}` for lowercase identifiers; `$${` yields a literal `${`.
+// Anything else (`$VAR`, `${DESTDIR}`, `${x:-y}`) is left for the shell, and so
+// are unknown names in shell text (`${srcdir}`, a loop's `${f}`). Elsewhere an
+// unknown name is a typo and an error.
+fn expand(template: &str, vars: &HashMap<String, String>, shell: bool) -> Result<String, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+
+        if let Some(after) = tail.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+            continue;
+        }
+
+        let var = tail
+            .strip_prefix("${")
+            .and_then(|t| t.split_once('}'))
+            .filter(|(name, _)| is_var_name(name));
+
+        match var {
+            Some((name, after)) => match vars.get(name) {
+                Some(value) => {
+                    out.push_str(value);
+                    rest = after;
+                }
+                None if shell => {
+                    out.push('$');
+                    rest = &tail[1..];
+                }
+                None => return Err(format!("undefined variable '${{{}}}'", name)),
+            },
+            None => {
+                out.push('$');
+                rest = &tail[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// "ssl? cmd" -> ("ssl", true, "cmd"), "!ssl? cmd" -> ("ssl", false, "cmd")
+fn conditional(entry: &str) -> Option<(&str, bool, &str)> {
+    let (condition, rest) = entry.trim_start().split_once("? ")?;
+    let (name, wanted) = match condition.strip_prefix('!') {
+        Some(name) => (name, false),
+        None => (condition, true),
+    };
+    let is_option = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    is_option.then_some((name, wanted, rest.trim_start()))
+}
+
+fn is_var_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+// Relative path without '..' components
+fn is_contained(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> HashMap<String, String> {
+        HashMap::from([
+            ("name".to_string(), "curl".to_string()),
+            ("version".to_string(), "8.5.0".to_string()),
+        ])
+    }
+
+    #[test]
+    fn expand_substitutes_known_variables_only() {
+        let vars = vars();
+        assert_eq!(
+            expand("${name}-${version}.tar.xz", &vars, false).unwrap(),
+            "curl-8.5.0.tar.xz"
+        );
+        assert_eq!(expand("$${name}", &vars, false).unwrap(), "${name}");
+        assert_eq!(
+            expand("$DESTDIR ${DESTDIR} ${HOME:-x}", &vars, false).unwrap(),
+            "$DESTDIR ${DESTDIR} ${HOME:-x}"
+        );
+        assert_eq!(
+            expand("for f in *; do echo ${f}; done", &vars, true).unwrap(),
+            "for f in *; do echo ${f}; done"
+        );
+        assert!(expand("${nmae}", &vars, false).is_err());
+    }
+
+    #[test]
+    fn conditional_parses_option_prefixes() {
+        assert_eq!(conditional("ssl? openssl"), Some(("ssl", true, "openssl")));
+        assert_eq!(
+            conditional("  !docs?   make doc"),
+            Some(("docs", false, "make doc"))
+        );
+        assert_eq!(conditional("openssl"), None);
+        assert_eq!(conditional("test -f x? y"), None);
+        assert_eq!(conditional("? cmd"), None);
+    }
 }
@@ -0,0 +1,89 @@
+use crate::core::{PackageName, RavenError, Recipe};
+use crate::postprocess::remove_empty_parents;
+use globset::{Glob, GlobSetBuilder};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+// Blocking: distribute the installed tree over the recipe's packages under
+// `root/<package>/`. Sub-packages claim files by glob in declaration order;
+// the main package keeps whatever is left and split-off debug info goes to
+// `<name>-dbg`. Returns the package trees, main package first.
+pub fn split(
+    recipe: &Recipe,
+    out: &Path,
+    dbg: &Path,
+    root: &Path,
+) -> Result<Vec<(PackageName, PathBuf)>, RavenError> {
+    if root.exists() {
+        std::fs::remove_dir_all(root)?;
+    }
+    std::fs::create_dir_all(root)?;
+
+    let mut packages = Vec::new();
+
+    for sub in &recipe.packages {
+        let mut globs = GlobSetBuilder::new();
+        for pattern in &sub.files {
+            globs.add(Glob::new(pattern.trim_start_matches('/')).map_err(|e| {
+                RavenError::InvalidRecipe(format!("{}: bad glob '{}': {}", sub.name.0, pattern, e))
+            })?);
+        }
+        let globs = globs
+            .build()
+            .map_err(|e| RavenError::InvalidRecipe(format!("{}: {}", sub.name.0, e)))?;
+
+        let claimed: Vec<PathBuf> = WalkDir::new(out)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| !e.file_type().is_dir())
+            .map(|e| e.into_path())
+            .filter(|p| globs.is_match(p.strip_prefix(out).unwrap()))
+            .collect();
+
+        let dest = root.join(&sub.name.0);
+        std::fs::create_dir_all(&dest)?;
+        for path in claimed {
+            move_into(&path, out, &dest)?;
+        }
+        packages.push((sub.name.clone(), dest));
+    }
+
+    // A declared `<name>-dbg` package receives the split debug info as well
+    let debug_name = recipe.debug_package();
+    if dbg.exists() {
+        let dest = root.join(&debug_name.0);
+        if dest.exists() {
+            let files: Vec<PathBuf> = WalkDir::new(dbg)
+                .min_depth(1)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| !e.file_type().is_dir())
+                .map(|e| e.into_path())
+                .collect();
+            for path in files {
+                move_into(&path, dbg, &dest)?;
+            }
+        } else {
+            std::fs::rename(dbg, &dest)?;
+            packages.push((debug_name, dest));
+        }
+    }
+
+    let main = root.join(&recipe.name.0);
+    std::fs::rename(out, &main)?;
+    std::fs::create_dir_all(out)?;
+    packages.insert(0, (recipe.name.clone(), main));
+
+    Ok(packages)
+}
+
+fn move_into(path: &Path, from: &Path, to: &Path) -> Result<(), RavenError> {
+    let dest = to.join(path.strip_prefix(from).unwrap());
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(path, &dest)?;
+    remove_empty_parents(path, from);
+    Ok(())
+}
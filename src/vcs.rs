@@ -0,0 +1,189 @@
+use crate::core::RavenError;
+use git2::{ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+// A `git+<url>#commit=<sha>` source. The commit id pins the content the way
+// sha256 does for tarballs.
+#[derive(Debug, Clone)]
+pub struct GitSource {
+    pub url: String,
+    pub commit: String,
+}
+
+impl GitSource {
+    pub fn is_git(url: &str) -> bool {
+        url.starts_with("git+")
+    }
+
+    pub fn parse(url: &str) -> Result<Self, RavenError> {
+        let rest = url
+            .strip_prefix("git+")
+            .ok_or_else(|| RavenError::InvalidRecipe(format!("Not a git source: {}", url)))?;
+
+        let (repo_url, fragment) = rest.split_once('#').ok_or_else(|| {
+            RavenError::InvalidRecipe(format!(
+                "Git source must be pinned with #commit=<sha>: {}",
+                url
+            ))
+        })?;
+
+        let commit = fragment
+            .split('&')
+            .find_map(|kv| kv.strip_prefix("commit="))
+            .ok_or_else(|| {
+                RavenError::InvalidRecipe(format!(
+                    "Git source must be pinned with #commit=<sha>: {}",
+                    url
+                ))
+            })?;
+
+        if commit.len() != 40 || !commit.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(RavenError::InvalidRecipe(format!(
+                "Git commit must be a full 40-character sha: {}",
+                commit
+            )));
+        }
+
+        Ok(Self {
+            url: repo_url.to_string(),
+            commit: commit.to_lowercase(),
+        })
+    }
+
+    // e.g. "https://host/group/foo.git" -> "foo"
+    pub fn repo_name(&self) -> String {
+        let name = self
+            .url
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .unwrap_or("source");
+        name.trim_end_matches(".git").to_string()
+    }
+
+    // Blocking: clone or update the bare mirror under `cache_dir` until it contains the commit
+    pub fn fetch(&self, cache_dir: &Path, offline: bool) -> Result<PathBuf, RavenError> {
+        let key = hex::encode(Sha256::digest(self.url.as_bytes()));
+        let repo_path = cache_dir.join(format!("{}-{}", self.repo_name(), &key[..16]));
+
+        let repo = if repo_path.exists() {
+            Repository::open_bare(&repo_path)?
+        } else if offline {
+            return Err(RavenError::Offline(format!(
+                "{} is not in the git cache",
+                self.url
+            )));
+        } else {
+            std::fs::create_dir_all(&repo_path)?;
+            let repo = Repository::init_bare(&repo_path)?;
+            repo.remote("origin", &self.url)?;
+            repo
+        };
+
+        let oid = Oid::from_str(&self.commit)?;
+        if repo.find_commit(oid).is_err() {
+            if offline {
+                return Err(RavenError::Offline(format!(
+                    "Commit {} of {} is not in the git cache",
+                    self.commit, self.url
+                )));
+            }
+            let mut remote = repo.find_remote("origin")?;
+            remote.fetch(
+                &["+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"],
+                None,
+                None,
+            )?;
+            repo.find_commit(oid).map_err(|_| {
+                RavenError::InvalidRecipe(format!(
+                    "Commit {} not found in {}",
+                    self.commit, self.url
+                ))
+            })?;
+        }
+
+        Ok(repo_path)
+    }
+
+    // Blocking: write the tree of the pinned commit as a tarball whose bytes depend
+    // only on the commit (sorted entries, commit timestamp, root ownership).
+    pub fn archive(&self, repo_path: &Path, out: &Path) -> Result<(), RavenError> {
+        let repo = Repository::open_bare(repo_path)?;
+        let commit = repo.find_commit(Oid::from_str(&self.commit)?)?;
+        let tree = commit.tree()?;
+        let mtime = commit.time().seconds().max(0) as u64;
+        let prefix = format!("{}-{}", self.repo_name(), &self.commit[..12]);
+
+        let mut builder = tar::Builder::new(File::create(out)?);
+        let mut walk_error = None;
+
+        let header_for = |kind: tar::EntryType, mode: u32, size: u64| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(kind);
+            header.set_mode(mode);
+            header.set_size(size);
+            header.set_mtime(mtime);
+            header.set_uid(0);
+            header.set_gid(0);
+            header
+        };
+
+        let mut root = header_for(tar::EntryType::Directory, 0o755, 0);
+        builder.append_data(&mut root, format!("{}/", prefix), std::io::empty())?;
+
+        tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+            let name = match entry.name() {
+                Some(n) => n,
+                None => return TreeWalkResult::Skip,
+            };
+            let path = format!("{}/{}{}", prefix, dir, name);
+
+            let result = (|| -> Result<(), RavenError> {
+                match entry.kind() {
+                    Some(ObjectType::Tree) => {
+                        let mut header = header_for(tar::EntryType::Directory, 0o755, 0);
+                        builder.append_data(&mut header, format!("{}/", path), std::io::empty())?;
+                    }
+                    Some(ObjectType::Blob) => {
+                        let blob = repo.find_blob(entry.id())?;
+                        let content = blob.content();
+                        match entry.filemode() {
+                            0o120000 => {
+                                let target = String::from_utf8_lossy(content).to_string();
+                                let mut header = header_for(tar::EntryType::Symlink, 0o777, 0);
+                                builder.append_link(&mut header, &path, target)?;
+                            }
+                            mode => {
+                                let perm = if mode & 0o111 != 0 { 0o755 } else { 0o644 };
+                                let mut header =
+                                    header_for(tar::EntryType::Regular, perm, content.len() as u64);
+                                builder.append_data(&mut header, &path, content)?;
+                            }
+                        }
+                    }
+                    // Submodules (commit entries) are not followed
+                    _ => {}
+                }
+                Ok(())
+            })();
+
+            match result {
+                Ok(()) => TreeWalkResult::Ok,
+                Err(e) => {
+                    walk_error = Some(e);
+                    TreeWalkResult::Abort
+                }
+            }
+        })
+        .or_else(|e| if walk_error.is_some() { Ok(()) } else { Err(e) })?;
+
+        if let Some(e) = walk_error {
+            return Err(e);
+        }
+
+        builder.into_inner()?;
+        Ok(())
+    }
+}